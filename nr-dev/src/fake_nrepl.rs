@@ -0,0 +1,520 @@
+// fake_nrepl.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! A scriptable stand-in for an nREPL server.
+//!
+//! The server listens on a random local port and answers the `clone`,
+//! `describe`, `eval`, `interrupt`, `stdin`, and `close` operations.  What the
+//! evaluations produce is scripted up front with [`Script`]; unscripted code
+//! evaluates to itself.  The requests are kept for the tests to inspect.
+
+use std::{
+  collections::{BTreeMap, HashMap, VecDeque},
+  io::{self, Read, Write},
+  net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+  str,
+  sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  thread, time,
+};
+
+use nreplops_tool::bencode;
+use serde_bencode::value::Value;
+
+/// What the server does while evaluating
+#[derive(Clone, Debug)]
+pub enum Reply {
+  Out(String),
+  Err(String),
+  Value(String),
+  /// Throws an exception of the given class
+  Ex(String),
+  /// Asks for input and waits for it; the input is echoed back as output
+  NeedInput,
+  /// Keeps evaluating until interrupted
+  Hang,
+  Sleep(time::Duration),
+  /// Closes the connection in the middle of the evaluation
+  Disconnect,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Script {
+  evals: Vec<(String, Vec<Reply>)>,
+  disconnect_on_clone: bool,
+}
+
+impl Script {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Answers the evaluation of `code` with the replies.  The `done` status is
+  /// sent after the last reply.
+  pub fn on_eval(
+    mut self,
+    code: &str,
+    replies: impl IntoIterator<Item = Reply>,
+  ) -> Self {
+    self
+      .evals
+      .push((code.to_owned(), replies.into_iter().collect()));
+    self
+  }
+
+  /// Closes the connection instead of creating a session.
+  pub fn disconnect_on_clone(mut self) -> Self {
+    self.disconnect_on_clone = true;
+    self
+  }
+
+  fn replies(&self, code: &str) -> VecDeque<Reply> {
+    self
+      .evals
+      .iter()
+      .find(|(c, _)| c == code)
+      .map(|(_, replies)| replies.iter().cloned().collect())
+      .unwrap_or_else(|| [Reply::Value(code.to_owned())].into())
+  }
+}
+
+/// A request as received by the server
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+  pub fields: BTreeMap<String, Value>,
+}
+
+impl Request {
+  pub fn op(&self) -> &str {
+    self.get("op").unwrap_or_default()
+  }
+
+  /// Returns the field if it is a string.
+  pub fn get(&self, key: &str) -> Option<&str> {
+    match self.fields.get(key)? {
+      Value::Bytes(bytes) => str::from_utf8(bytes).ok(),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct FakeServer {
+  addr: SocketAddr,
+  requests: Arc<Mutex<Vec<Request>>>,
+  stopping: Arc<AtomicBool>,
+  acceptor: Option<thread::JoinHandle<()>>,
+}
+
+impl FakeServer {
+  /// Starts listening on a random port on the loopback interface.
+  pub fn start(script: Script) -> io::Result<Self> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let stopping = Arc::new(AtomicBool::new(false));
+    let acceptor = thread::spawn({
+      let script = Arc::new(script);
+      let requests = requests.clone();
+      let stopping = stopping.clone();
+      let session_count = Arc::new(AtomicUsize::new(0));
+      move || {
+        for stream in listener.incoming() {
+          if stopping.load(Ordering::SeqCst) {
+            break;
+          }
+          let Ok(stream) = stream else { continue };
+          let mut conn = Connection {
+            stream,
+            buffer: Vec::new(),
+            script: script.clone(),
+            requests: requests.clone(),
+            session_count: session_count.clone(),
+            evals: VecDeque::new(),
+            input: String::new(),
+          };
+          thread::spawn(move || {
+            let _ = conn.serve();
+            let _ = conn.stream.shutdown(Shutdown::Both);
+          });
+        }
+      }
+    });
+    Ok(Self {
+      addr,
+      requests,
+      stopping,
+      acceptor: Some(acceptor),
+    })
+  }
+
+  pub fn addr(&self) -> SocketAddr {
+    self.addr
+  }
+
+  pub fn port(&self) -> u16 {
+    self.addr.port()
+  }
+
+  /// Returns the requests received so far in the order of arrival.
+  pub fn requests(&self) -> Vec<Request> {
+    self.requests.lock().unwrap().clone()
+  }
+
+  /// Returns the operations of the requests received so far.
+  pub fn ops(&self) -> Vec<String> {
+    self.requests().iter().map(|r| r.op().to_owned()).collect()
+  }
+}
+
+impl Drop for FakeServer {
+  fn drop(&mut self) {
+    // Wake up the acceptor so that it notices it should stop
+    self.stopping.store(true, Ordering::SeqCst);
+    let _ = TcpStream::connect(self.addr);
+    if let Some(acceptor) = self.acceptor.take() {
+      let _ = acceptor.join();
+    }
+  }
+}
+
+/// An evaluation in progress
+struct Eval {
+  request: Request,
+  replies: VecDeque<Reply>,
+  asked_input: bool,
+}
+
+struct Connection {
+  stream: TcpStream,
+  buffer: Vec<u8>,
+  script: Arc<Script>,
+  requests: Arc<Mutex<Vec<Request>>>,
+  session_count: Arc<AtomicUsize>,
+  /// The evaluations in the order they arrived; only the first one runs
+  evals: VecDeque<Eval>,
+  /// The input received but not yet consumed
+  input: String,
+}
+
+/// Signals that the connection should be closed
+struct Hangup;
+
+impl From<io::Error> for Hangup {
+  fn from(_: io::Error) -> Self {
+    Hangup
+  }
+}
+
+impl Connection {
+  fn serve(&mut self) -> Result<(), Hangup> {
+    loop {
+      self.run_evals()?;
+      let request = self.read_request()?;
+      self.requests.lock().unwrap().push(request.clone());
+      self.handle(request)?;
+    }
+  }
+
+  fn handle(&mut self, request: Request) -> Result<(), Hangup> {
+    match request.op() {
+      "clone" => {
+        if self.script.disconnect_on_clone {
+          return Err(Hangup);
+        }
+        let n = self.session_count.fetch_add(1, Ordering::SeqCst) + 1;
+        self.reply(
+          &request,
+          [
+            ("new-session", bytes(&format!("fake-session-{}", n))),
+            ("status", status(&["done"])),
+          ],
+        )
+      }
+      "describe" => {
+        let ops = ["clone", "close", "describe", "eval", "interrupt", "stdin"]
+          .iter()
+          .map(|op| (op.as_bytes().to_vec(), Value::Dict(HashMap::new())))
+          .collect();
+        self.reply(
+          &request,
+          [("ops", Value::Dict(ops)), ("status", status(&["done"]))],
+        )
+      }
+      "eval" => {
+        let replies = self.script.replies(request.get("code").unwrap_or(""));
+        self.evals.push_back(Eval {
+          request,
+          replies,
+          asked_input: false,
+        });
+        Ok(())
+      }
+      "interrupt" => {
+        let running = self.evals.front().map(|e| e.request.get("id"));
+        if running.is_some() && running == Some(request.get("interrupt-id")) {
+          let eval = self.evals.pop_front().unwrap();
+          self.reply(
+            &eval.request,
+            [("status", status(&["interrupted", "done"]))],
+          )?;
+          self.reply(&request, [("status", status(&["done"]))])
+        } else {
+          self.reply(&request, [("status", status(&["session-idle", "done"]))])
+        }
+      }
+      "stdin" => {
+        self.input.push_str(request.get("stdin").unwrap_or(""));
+        self.reply(&request, [("status", status(&["done"]))])
+      }
+      "close" => {
+        self.reply(&request, [("status", status(&["done", "session-closed"]))])
+      }
+      _ => self.reply(
+        &request,
+        [("status", status(&["error", "unknown-op", "done"]))],
+      ),
+    }
+  }
+
+  /// Runs the evaluations until one of them blocks or they all are done.
+  fn run_evals(&mut self) -> Result<(), Hangup> {
+    while let Some(mut eval) = self.evals.pop_front() {
+      while let Some(reply) = eval.replies.front().cloned() {
+        match reply {
+          Reply::Out(s) => self.reply(&eval.request, [("out", bytes(&s))])?,
+          Reply::Err(s) => self.reply(&eval.request, [("err", bytes(&s))])?,
+          Reply::Value(s) => {
+            self.reply(&eval.request, [("value", bytes(&s))])?
+          }
+          Reply::Ex(class) => self.reply(
+            &eval.request,
+            [
+              ("ex", bytes(&class)),
+              ("root-ex", bytes(&class)),
+              ("status", status(&["eval-error"])),
+            ],
+          )?,
+          Reply::NeedInput if self.input.is_empty() => {
+            if !eval.asked_input {
+              self
+                .reply(&eval.request, [("status", status(&["need-input"]))])?;
+              eval.asked_input = true;
+            }
+            self.evals.push_front(eval);
+            return Ok(());
+          }
+          Reply::NeedInput => {
+            let input = std::mem::take(&mut self.input);
+            self.reply(&eval.request, [("out", bytes(&input))])?;
+            eval.asked_input = false;
+          }
+          Reply::Hang => {
+            self.evals.push_front(eval);
+            return Ok(());
+          }
+          Reply::Sleep(duration) => thread::sleep(duration),
+          Reply::Disconnect => return Err(Hangup),
+        }
+        eval.replies.pop_front();
+      }
+      self.reply(&eval.request, [("status", status(&["done"]))])?;
+    }
+    Ok(())
+  }
+
+  fn read_request(&mut self) -> Result<Request, Hangup> {
+    loop {
+      if let Some(end) = scan(&self.buffer).map_err(|_| Hangup)? {
+        let message = serde_bencode::from_bytes::<Value>(&self.buffer[..end])
+          .map_err(|_| Hangup)?;
+        self.buffer.drain(..end);
+        let Value::Dict(fields) = message else {
+          return Err(Hangup);
+        };
+        let fields = fields
+          .into_iter()
+          .map(|(k, v)| (String::from_utf8_lossy(&k).into_owned(), v))
+          .collect();
+        return Ok(Request { fields });
+      }
+      let mut chunk = [0; 4096];
+      match self.stream.read(&mut chunk)? {
+        0 => return Err(Hangup),
+        len => self.buffer.extend_from_slice(&chunk[..len]),
+      }
+    }
+  }
+
+  /// Sends a response to the request with the request's `id` and `session`.
+  /// Like nREPL, the requests without a session are answered from an
+  /// ephemeral one.
+  fn reply<'a>(
+    &mut self,
+    request: &Request,
+    fields: impl IntoIterator<Item = (&'a str, Value)>,
+  ) -> Result<(), Hangup> {
+    let mut response = fields
+      .into_iter()
+      .map(|(k, v)| (k.as_bytes().to_vec(), v))
+      .collect::<HashMap<_, _>>();
+    response.insert(b"session".to_vec(), bytes("fake-ephemeral-session"));
+    for key in ["id", "session"] {
+      if let Some(value) = request.fields.get(key) {
+        response.insert(key.as_bytes().to_vec(), value.clone());
+      }
+    }
+    let payload =
+      serde_bencode::to_bytes(&Value::Dict(response)).map_err(|_| Hangup)?;
+    self.stream.write_all(&payload)?;
+    Ok(())
+  }
+}
+
+fn bytes(s: &str) -> Value {
+  Value::Bytes(s.as_bytes().to_vec())
+}
+
+fn status(labels: &[&str]) -> Value {
+  Value::List(labels.iter().map(|s| bytes(s)).collect())
+}
+
+/// Returns the length of the bencoded value at the start of the buffer or
+/// `None` if the value is not complete yet.
+fn scan(buf: &[u8]) -> Result<Option<usize>, bencode::Error> {
+  match bencode::scan_next(buf) {
+    Ok((_, len)) => Ok(Some(len)),
+    Err(bencode::Error::UnexpectedEnd) => Ok(None),
+    Err(err) => Err(err),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  struct Client {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+  }
+
+  impl Client {
+    fn connect(server: &FakeServer) -> Self {
+      Self {
+        stream: TcpStream::connect(server.addr()).unwrap(),
+        buffer: Vec::new(),
+      }
+    }
+
+    fn send(&mut self, fields: &[(&str, &str)]) {
+      let message = fields
+        .iter()
+        .map(|(k, v)| (k.as_bytes().to_vec(), bytes(v)))
+        .collect::<HashMap<_, _>>();
+      let payload = serde_bencode::to_bytes(&Value::Dict(message)).unwrap();
+      self.stream.write_all(&payload).unwrap();
+    }
+
+    fn recv(&mut self) -> Request {
+      loop {
+        if let Some(end) = scan(&self.buffer).unwrap() {
+          let Value::Dict(fields) =
+            serde_bencode::from_bytes(&self.buffer[..end]).unwrap()
+          else {
+            panic!("not a dictionary");
+          };
+          self.buffer.drain(..end);
+          let fields = fields
+            .into_iter()
+            .map(|(k, v)| (String::from_utf8(k).unwrap(), v))
+            .collect();
+          return Request { fields };
+        }
+        let mut chunk = [0; 4096];
+        let len = self.stream.read(&mut chunk).unwrap();
+        assert!(len > 0, "server disconnected");
+        self.buffer.extend_from_slice(&chunk[..len]);
+      }
+    }
+
+    fn status(&mut self) -> Value {
+      self.recv().fields.remove("status").unwrap()
+    }
+  }
+
+  #[test]
+  fn scanning() {
+    assert_eq!(scan(b"i42e"), Ok(Some(4)));
+    assert_eq!(scan(b"d2:op4:evale"), Ok(Some(12)));
+    assert_eq!(scan(b"d2:op4:ev"), Ok(None));
+    assert_eq!(scan(b"l1:ai1"), Ok(None));
+    assert_eq!(scan(b""), Ok(None));
+    assert_eq!(scan(b"x"), Err(bencode::Error::BadInput));
+  }
+
+  #[test]
+  fn evaluates_scripted_and_unscripted_code() {
+    let server = FakeServer::start(
+      Script::new()
+        .on_eval("(f)", [Reply::Out("hi\n".into()), Reply::Value("1".into())]),
+    )
+    .unwrap();
+    let mut client = Client::connect(&server);
+    client.send(&[("op", "clone"), ("id", "1")]);
+    let session = client.recv();
+    assert_eq!(session.get("new-session"), Some("fake-session-1"));
+    client.send(&[("op", "eval"), ("id", "2"), ("code", "(f)")]);
+    assert_eq!(client.recv().get("out"), Some("hi\n"));
+    assert_eq!(client.recv().get("value"), Some("1"));
+    assert_eq!(client.status(), status(&["done"]));
+    client.send(&[("op", "eval"), ("id", "3"), ("code", "(g)")]);
+    assert_eq!(client.recv().get("value"), Some("(g)"));
+    assert_eq!(client.status(), status(&["done"]));
+    client.send(&[("op", "frobnicate"), ("id", "4")]);
+    assert_eq!(client.status(), status(&["error", "unknown-op", "done"]));
+    assert_eq!(server.ops(), ["clone", "eval", "eval", "frobnicate"]);
+  }
+
+  #[test]
+  fn input_and_interrupts() {
+    let server = FakeServer::start(
+      Script::new()
+        .on_eval("(read-line)", [Reply::NeedInput])
+        .on_eval("(loop [] (recur))", [Reply::Hang]),
+    )
+    .unwrap();
+    let mut client = Client::connect(&server);
+    client.send(&[("op", "eval"), ("id", "1"), ("code", "(read-line)")]);
+    assert_eq!(client.status(), status(&["need-input"]));
+    client.send(&[("op", "stdin"), ("id", "2"), ("stdin", "yes\n")]);
+    assert_eq!(client.recv().get("id"), Some("2"));
+    let response = client.recv();
+    assert_eq!(response.get("id"), Some("1"));
+    assert_eq!(response.get("out"), Some("yes\n"));
+    assert_eq!(client.status(), status(&["done"]));
+
+    client.send(&[("op", "eval"), ("id", "3"), ("code", "(loop [] (recur))")]);
+    client.send(&[("op", "interrupt"), ("id", "4"), ("interrupt-id", "3")]);
+    let response = client.recv();
+    assert_eq!(response.get("id"), Some("3"));
+    assert_eq!(
+      response.fields.get("status"),
+      Some(&status(&["interrupted", "done"]))
+    );
+    assert_eq!(client.status(), status(&["done"]));
+  }
+}
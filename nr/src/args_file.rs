@@ -0,0 +1,300 @@
+// args_file.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Loading template arguments from EDN, JSON, and TOML files.
+//!
+//! The file holds a map from the argument names to the values.  The values are
+//! turned into Clojure literals through the result IR and the printer so that
+//! maps and vectors arrive intact.
+
+use std::{fs, path::Path, rc::Rc};
+
+use crate::{
+  cli::TemplateArg,
+  clojure::{
+    lex::{self, Lexeme as L},
+    literal,
+    result_ir::{self, MapEntry, Value},
+  },
+  error::Error,
+  pprint,
+};
+
+/// Loads the arguments from the file; the format is picked by the extension.
+pub fn load(path: &Path) -> Result<Vec<TemplateArg>, Error> {
+  let name = path.to_string_lossy();
+  let content = fs::read_to_string(path)
+    .map_err(|_| Error::CannotReadFile(name.to_string()))?;
+  let entries = match path.extension().and_then(|e| e.to_str()) {
+    Some("edn") => from_edn(&content),
+    Some("json") => from_json(&content),
+    Some("toml") => from_toml(&content),
+    _ => Err("expected an .edn, .json, or .toml file".to_owned()),
+  }
+  .map_err(|reason| Error::BadArgsFile(name.to_string(), reason))?;
+  Ok(
+    entries
+      .into_iter()
+      .map(|(name, value)| TemplateArg {
+        pos: None,
+        name: Some(Rc::from(name)),
+        value: Rc::from(value),
+        literal: true,
+      })
+      .collect(),
+  )
+}
+
+type Entries = Vec<(String, String)>;
+
+fn from_edn(content: &str) -> Result<Entries, String> {
+  let lexemes = lex::lex(content).map_err(|_| "cannot parse EDN".to_owned())?;
+  // The result IR covers EDN and not much else
+  if let Some(lexeme) = lexemes.iter().find(|l| !is_edn(l)) {
+    return Err(format!("unsupported syntax: {}", lexeme.source().trim()));
+  }
+  let Ok(Value::Map { entries }) = result_ir::build(&lexemes) else {
+    return Err("expected a single map".to_owned());
+  };
+  entries
+    .iter()
+    .map(|MapEntry { key, value }| {
+      let name = match *key {
+        Value::Keyword {
+          namespace: None,
+          name,
+          alias: false,
+        }
+        | Value::Symbol {
+          namespace: None,
+          name,
+        } => name.to_owned(),
+        // The literal is lexed anew for its value with the escapes resolved
+        Value::String { literal } => match lex::lex(literal).as_deref() {
+          Ok([L::String { value, .. }]) => literal::string_value(value),
+          _ => return Err(format!("bad key: {}", literal)),
+        },
+        _ => {
+          return Err("expected keywords, symbols, or strings as keys".into())
+        }
+      };
+      Ok((name, pprint::to_clojure(value)))
+    })
+    .collect()
+}
+
+fn is_edn(lexeme: &L) -> bool {
+  matches!(
+    lexeme,
+    L::Whitespace { .. }
+      | L::Comment { .. }
+      | L::Nil { .. }
+      | L::Boolean { .. }
+      | L::Numeric { .. }
+      | L::String { .. }
+      | L::SymbolicValuePrefix { .. }
+      | L::SymbolicValue { .. }
+      | L::Symbol { .. }
+      | L::Keyword { .. }
+      | L::Tag { .. }
+      | L::TaggedLiteral { .. }
+      | L::StartList { .. }
+      | L::EndList { .. }
+      | L::StartSet { .. }
+      | L::EndSet { .. }
+      | L::StartVector { .. }
+      | L::EndVector { .. }
+      | L::StartMap { .. }
+      | L::EndMap { .. }
+  )
+}
+
+fn from_json(content: &str) -> Result<Entries, String> {
+  match serde_json::from_str(content).map_err(|e| e.to_string())? {
+    serde_json::Value::Object(map) => Ok(
+      map
+        .into_iter()
+        .map(|(name, value)| (name, to_clojure(&Data::from(value))))
+        .collect(),
+    ),
+    _ => Err("expected an object".to_owned()),
+  }
+}
+
+fn from_toml(content: &str) -> Result<Entries, String> {
+  let table = content.parse::<toml::Table>().map_err(|e| e.to_string())?;
+  Ok(
+    table
+      .into_iter()
+      .map(|(name, value)| (name, to_clojure(&Data::from(value))))
+      .collect(),
+  )
+}
+
+/// A value read from a JSON or TOML file with the scalars as Clojure literals
+///
+/// The result IR only borrows the literals so they need to live somewhere.
+enum Data {
+  Nil,
+  Boolean(bool),
+  Number(String),
+  String(String),
+  SymbolicValue(&'static str),
+  Vector(Vec<Data>),
+  Map(Vec<(Data, Data)>),
+}
+
+impl Data {
+  fn to_ir(&self) -> Value<'_> {
+    match self {
+      Data::Nil => Value::Nil,
+      Data::Boolean(value) => Value::Boolean { value: *value },
+      Data::Number(literal) => Value::Number { literal },
+      Data::String(literal) => Value::String { literal },
+      Data::SymbolicValue(literal) => Value::SymbolicValue { literal },
+      Data::Vector(values) => Value::Vector {
+        values: values.iter().map(Data::to_ir).collect(),
+      },
+      Data::Map(entries) => Value::Map {
+        entries: entries
+          .iter()
+          .map(|(key, value)| MapEntry {
+            key: key.to_ir(),
+            value: value.to_ir(),
+          })
+          .collect(),
+      },
+    }
+  }
+
+  fn float(value: f64) -> Self {
+    if value.is_nan() {
+      Data::SymbolicValue("NaN")
+    } else if value.is_infinite() && value > 0.0 {
+      Data::SymbolicValue("Inf")
+    } else if value.is_infinite() {
+      Data::SymbolicValue("-Inf")
+    } else {
+      Data::Number(format!("{:?}", value))
+    }
+  }
+}
+
+fn to_clojure(data: &Data) -> String {
+  pprint::to_clojure(&data.to_ir())
+}
+
+impl From<serde_json::Value> for Data {
+  fn from(value: serde_json::Value) -> Self {
+    use serde_json::Value as J;
+    match value {
+      J::Null => Data::Nil,
+      J::Bool(value) => Data::Boolean(value),
+      J::Number(n) => match n.as_f64() {
+        Some(x) if n.is_f64() => Data::float(x),
+        _ => Data::Number(n.to_string()),
+      },
+      J::String(s) => Data::String(literal::string(&s)),
+      J::Array(values) => {
+        Data::Vector(values.into_iter().map(Data::from).collect())
+      }
+      J::Object(map) => Data::Map(
+        map
+          .into_iter()
+          .map(|(k, v)| (Data::String(literal::string(&k)), Data::from(v)))
+          .collect(),
+      ),
+    }
+  }
+}
+
+impl From<toml::Value> for Data {
+  fn from(value: toml::Value) -> Self {
+    use toml::Value as T;
+    match value {
+      T::Boolean(value) => Data::Boolean(value),
+      T::Integer(n) => Data::Number(n.to_string()),
+      T::Float(x) => Data::float(x),
+      T::String(s) => Data::String(literal::string(&s)),
+      T::Datetime(d) => Data::String(literal::string(&d.to_string())),
+      T::Array(values) => {
+        Data::Vector(values.into_iter().map(Data::from).collect())
+      }
+      T::Table(table) => Data::Map(
+        table
+          .into_iter()
+          .map(|(k, v)| (Data::String(literal::string(&k)), Data::from(v)))
+          .collect(),
+      ),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn reads_edn() {
+    assert_eq!(
+      from_edn(
+        r#"; Arguments
+           {:n 1, x "a", "y" {:k [1 2.0 nil]}, "a\"b" 2,
+            :z #inst "2024-01-01"}"#
+      )
+      .unwrap(),
+      [
+        ("n".to_owned(), "1".to_owned()),
+        ("x".to_owned(), r#""a""#.to_owned()),
+        ("y".to_owned(), "{:k [1 2.0 nil]}".to_owned()),
+        (r#"a"b"#.to_owned(), "2".to_owned()),
+        ("z".to_owned(), r#"#inst "2024-01-01""#.to_owned()),
+      ]
+    );
+    assert!(from_edn("[1 2]").is_err());
+    assert!(from_edn("{:a 1} {:b 2}").is_err());
+    assert!(from_edn("{:a 'x}").is_err());
+    assert!(from_edn("{[1] 2}").is_err());
+  }
+
+  #[test]
+  fn reads_json() {
+    assert_eq!(
+      from_json(
+        r#"{"n": 1, "x": "say \"hi\"", "y": {"k": [1.5, null, true]}}"#
+      )
+      .unwrap(),
+      [
+        ("n".to_owned(), "1".to_owned()),
+        ("x".to_owned(), r#""say \"hi\"""#.to_owned()),
+        ("y".to_owned(), r#"{"k" [1.5 nil true]}"#.to_owned()),
+      ]
+    );
+    assert!(from_json("[1]").is_err());
+    assert!(from_json("{").is_err());
+  }
+
+  #[test]
+  fn reads_toml() {
+    assert_eq!(
+      from_toml("n = 1\nx = 'a'\n[y]\nk = [1, inf]\n").unwrap(),
+      [
+        ("n".to_owned(), "1".to_owned()),
+        ("x".to_owned(), r#""a""#.to_owned()),
+        ("y".to_owned(), r#"{"k" [1 ##Inf]}"#.to_owned()),
+      ]
+    );
+  }
+}
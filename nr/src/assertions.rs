@@ -0,0 +1,212 @@
+// assertions.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Assertions about the identity of the nREPL host.
+//!
+//! These are checked in a fresh session before any user forms are evaluated.
+//! The idea is to guard against reaching the wrong box, which is easy to do
+//! when tunneling through a stable localhost port (e.g. over a VPN).
+
+use std::{fmt, str};
+
+use crate::{clojure::literal, error::Error, nrepl::Session};
+
+/// Prefix that marks a directive in the script's header comments
+const DIRECTIVE_PREFIX: &str = "nr:";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostAssertion {
+  /// The host's name must equal the given one.
+  Hostname(String),
+  /// The host's environment variable must have the given value.
+  EnvVar { name: String, value: String },
+  /// The given form must evaluate to a truthy value on the host.
+  Form(String),
+}
+
+impl HostAssertion {
+  /// Parses a script directive like `nr:assert-hostname app-1`.
+  ///
+  /// The input is the text of a comment line with the comment markers
+  /// stripped.  Returns `None` if the line is not a directive at all.
+  pub fn parse_directive(
+    line: &str,
+  ) -> Option<Result<Self, ParseHostAssertionError>> {
+    let directive = line.trim().strip_prefix(DIRECTIVE_PREFIX)?;
+    if directive.is_empty() || directive.starts_with(char::is_whitespace) {
+      return None;
+    }
+    let (name, arg) = directive
+      .split_once(char::is_whitespace)
+      .map(|(n, a)| (n, a.trim()))
+      .unwrap_or((directive, ""));
+    Some(match name {
+      "assert-hostname" if !arg.is_empty() => {
+        Ok(HostAssertion::Hostname(arg.to_owned()))
+      }
+      "assert-env" => arg.parse::<EnvAssertion>().map(|e| e.0),
+      "assert-form" if !arg.is_empty() => {
+        Ok(HostAssertion::Form(arg.to_owned()))
+      }
+      _ => Err(ParseHostAssertionError),
+    })
+  }
+
+  /// Returns the code that, when evaluated on the host, produces the value
+  /// being asserted.
+  fn probe(&self) -> String {
+    match self {
+      HostAssertion::Hostname(_) => {
+        "(.getHostName (java.net.InetAddress/getLocalHost))".to_owned()
+      }
+      HostAssertion::EnvVar { name, .. } => {
+        format!("(System/getenv {})", literal::string(name))
+      }
+      HostAssertion::Form(form) => form.clone(),
+    }
+  }
+
+  /// Checks the printed result of the probe against the assertion.
+  fn accepts(&self, result: &str) -> bool {
+    match self {
+      HostAssertion::Hostname(expected)
+      | HostAssertion::EnvVar {
+        value: expected, ..
+      } => result == literal::string(expected),
+      HostAssertion::Form(_) => result != "nil" && result != "false",
+    }
+  }
+}
+
+impl fmt::Display for HostAssertion {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      HostAssertion::Hostname(name) => {
+        write!(f, "hostname {}", literal::string(name))
+      }
+      HostAssertion::EnvVar { name, value } => {
+        write!(f, "${}={}", name, literal::string(value))
+      }
+      HostAssertion::Form(form) => form.fmt(f),
+    }
+  }
+}
+
+/// Environment variable assertion in the `VAR=VALUE` form
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnvAssertion(pub HostAssertion);
+
+impl str::FromStr for EnvAssertion {
+  type Err = ParseHostAssertionError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.split_once('=') {
+      Some((name, value)) if !name.is_empty() => {
+        Ok(Self(HostAssertion::EnvVar {
+          name: name.to_owned(),
+          value: value.to_owned(),
+        }))
+      }
+      _ => Err(ParseHostAssertionError),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, thiserror::Error)]
+#[error("bad host assertion")]
+pub struct ParseHostAssertionError;
+
+/// Checks the assertions in order and fails on the first one that does not
+/// hold.
+pub fn check(
+  session: &mut Session,
+  assertions: &[HostAssertion],
+) -> Result<(), Error> {
+  for assertion in assertions {
+    let mut result = None;
+    let mut exception = None;
+    session.eval(&assertion.probe(), None, None, None, |response| {
+      if let Some(value) = response.value {
+        result = Some(value.to_owned());
+      }
+      if let Some(ex) = response.ex {
+        exception = Some(ex.to_owned());
+      }
+      Ok(())
+    })?;
+    match (result, exception) {
+      (_, Some(ex)) => {
+        return Err(Error::HostAssertionThrew(assertion.to_string(), ex))
+      }
+      (Some(value), None) if assertion.accepts(&value) => (),
+      (value, None) => {
+        return Err(Error::HostAssertionFailed(
+          assertion.to_string(),
+          value.unwrap_or_else(|| "nothing".to_owned()),
+        ))
+      }
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn parse_directives() {
+    let parse = HostAssertion::parse_directive;
+    assert_eq!(parse("just a comment"), None);
+    assert_eq!(parse(" nr: not a directive either"), None);
+    assert_eq!(parse("nr:bogus"), Some(Err(ParseHostAssertionError)));
+    assert_eq!(
+      parse(" nr:assert-hostname app-1"),
+      Some(Ok(HostAssertion::Hostname("app-1".to_owned())))
+    );
+    assert_eq!(
+      parse("nr:assert-env APP_ENV=prod"),
+      Some(Ok(HostAssertion::EnvVar {
+        name: "APP_ENV".to_owned(),
+        value: "prod".to_owned()
+      }))
+    );
+    assert_eq!(
+      parse("nr:assert-form (= 1 1)"),
+      Some(Ok(HostAssertion::Form("(= 1 1)".to_owned())))
+    );
+    assert_eq!(
+      parse("nr:assert-hostname"),
+      Some(Err(ParseHostAssertionError))
+    );
+    assert_eq!(
+      parse("nr:assert-env =prod"),
+      Some(Err(ParseHostAssertionError))
+    );
+  }
+
+  #[test]
+  fn accepting_results() {
+    let hostname = HostAssertion::Hostname("app-1".to_owned());
+    assert!(hostname.accepts(r#""app-1""#));
+    assert!(!hostname.accepts(r#""app-2""#));
+    assert!(!hostname.accepts("nil"));
+    let form = HostAssertion::Form("(foo)".to_owned());
+    assert!(form.accepts("true"));
+    assert!(form.accepts("0"));
+    assert!(!form.accepts("false"));
+    assert!(!form.accepts("nil"));
+  }
+}
@@ -21,13 +21,34 @@
   unused
 )]
 
-use std::{cmp, io::Write, process};
+use std::{cmp, fmt::Write as _, io::Write, path, process};
 
 use nreplops_tool::{self, error::Error, version, *};
 
 fn main() {
   let args = cli::Args::from_command_line().unwrap_or_else(die);
 
+  log::init(args.verbosity, args.trace_wire, args.log_file.as_deref())
+    .map_err(|_| {
+      Error::CannotWriteFile(
+        args.log_file.as_ref().unwrap().to_string_lossy().into(),
+      )
+    })
+    .unwrap_or_else(die);
+
+  // The help is shown even if the script requires another version
+  if args.script_help {
+    print!(
+      "{}",
+      script_help::render(
+        args.script.as_ref().expect("shebang mode has a script"),
+        args.version_range.as_ref()
+      )
+      .unwrap_or_else(die)
+    );
+    return;
+  }
+
   if let Some(ref required) = args.version_range {
     let current = version::crate_version();
     use cmp::Ordering::*;
@@ -38,27 +59,101 @@ fn main() {
     }
   }
 
-  let conn_expr = args.conn_expr_src.resolve_expr().unwrap_or_else(die);
+  if args.close_ssh_masters {
+    let count = ssh::close_control_masters().unwrap_or_else(die);
+    eprintln!("Closed {} SSH control master(s)", count);
+    return;
+  }
+
+  if args.which_port_file {
+    print!("{}", args.conn_expr_src.explain().unwrap_or_else(die));
+    return;
+  }
+
+  // There is no server to look for when replaying a recorded session
+  let conn_expr = if args.replay.is_none() {
+    Some(args.conn_expr_src.resolve_expr().unwrap_or_else(die))
+  } else {
+    None
+  };
 
   let host_opts_table =
     hosts_files::load_default_hosts_files().unwrap_or_else(die);
-  let sources =
-    sources::load_sources(&args.source_args[..], &args.template_args[..])
-      .unwrap_or_else(die);
+  let sources = sources::load_sources(
+    &args.source_args[..],
+    &args.template_args[..],
+    &args.env_prefix,
+  )
+  .unwrap_or_else(die);
 
   // Short-circuit if there is nothing to evaluate; effectively this happens
   // when the program is used only as a latch for the port file.
-  if sources.is_empty() {
+  if sources.is_empty() && !args.connect_options.handshake {
     return;
   }
 
-  let conn_routes =
-    routes::resolve_routes(&conn_expr, &host_opts_table).unwrap_or_else(die);
+  let conn_routes = conn_expr.as_ref().map(|conn_expr| {
+    let conn_routes =
+      routes::resolve_routes(conn_expr, &host_opts_table, &args.ssh_options)
+        .unwrap_or_else(die);
+    if conn_routes.is_tunneled() {
+      ssh::check_client().unwrap_or_else(die);
+    }
+    conn_routes
+  });
   let outputs = outputs::Outputs::try_from_args(&args).unwrap_or_else(die);
-  let socket = socket::connect(conn_routes).unwrap_or_else(die);
-  let con = nrepl::Connection::new(socket);
+
+  if args.dry_run {
+    dry_run(&sources, conn_routes, args.replay.as_deref(), &outputs)
+      .unwrap_or_else(die);
+    return;
+  }
+
+  let socket = match (conn_routes, args.replay.as_deref()) {
+    (Some(conn_routes), _) => {
+      socket::connect(conn_routes, &args.connect_options)
+    }
+    (None, Some(transcript)) => socket::Socket::replay(transcript),
+    (None, None) => unreachable!("either connecting or replaying"),
+  }
+  .unwrap_or_else(die);
+  let mut con = nrepl::Connection::new(socket);
+  if let Some(ref path) = args.record {
+    let recorder = transcript::Recorder::create(path)
+      .map_err(|_| Error::CannotWriteFile(path.to_string_lossy().into()))
+      .unwrap_or_else(die);
+    con.record(recorder);
+  }
   let mut session = con.session().unwrap_or_else(die);
 
+  // Likewise, when used only as a latch for the server
+  if sources.is_empty() {
+    session.close().unwrap_or_else(die);
+    return;
+  }
+
+  // Check that we have reached the right host before evaluating anything
+  let host_assertions = args
+    .host_assertions
+    .iter()
+    .chain(
+      conn_expr
+        .as_ref()
+        .and_then(|e| e.try_as_host_key())
+        .and_then(|k| host_opts_table.get(k))
+        .into_iter()
+        .flat_map(|opts| opts.assertions.iter()),
+    )
+    .chain(sources.iter().flat_map(|s| s.host_assertions.iter()))
+    .cloned()
+    .collect::<Vec<_>>();
+  if let Err(err) = assertions::check(&mut session, &host_assertions) {
+    if !matches!(err, Error::HostDisconnected) {
+      let _ = session.close();
+    }
+    return die(err);
+  }
+
   match eval_sources(&mut session, &sources, &outputs) {
     Ok(_) => {
       session.close().unwrap_or_else(die);
@@ -77,12 +172,46 @@ fn die<T>(e: Error) -> T {
   process::exit(1);
 }
 
+/// Shows the routes to the server and the forms that would be sent to it.
+fn dry_run(
+  sources: &[sources::Source],
+  conn_routes: Option<routes::Routes>,
+  replay: Option<&path::Path>,
+  outputs: &outputs::Outputs,
+) -> Result<(), Error> {
+  let mut text = String::new();
+  for route in conn_routes.into_iter().flatten() {
+    let _ = writeln!(text, ";; route: {}", route);
+  }
+  if let Some(transcript) = replay {
+    let _ = writeln!(text, ";; replay: {}", transcript.display());
+  }
+  for form in sources::forms(sources) {
+    let _ = write!(
+      text,
+      "\n;; line {} of {} in {}\n{}\n",
+      form.line,
+      form.file.unwrap_or("<input>"),
+      form.ns,
+      form.code
+    );
+  }
+  outputs
+    .exprs
+    .as_ref()
+    .expect("the dry run has a sink for the code")
+    .output(text.trim())
+}
+
 fn eval_sources(
   session: &mut nrepl::Session,
   sources: &[sources::Source],
   outputs: &outputs::Outputs,
 ) -> Result<(), Error> {
   for input in sources.iter() {
+    if let Some(ref sink) = outputs.exprs {
+      sink.output(&input.content)?;
+    }
     session.eval(&input.content, None, Some(1), Some(1), |response| {
       if let Some(value) = response.value {
         if let Some(ref sink) = outputs.nrepl_results {
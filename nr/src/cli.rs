@@ -14,18 +14,23 @@
 // the License.
 
 use std::{
-  env, ffi,
+  env, ffi, fs,
   io::{self, IsTerminal},
   path,
   rc::Rc,
   time,
 };
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
 
 use crate::{
-  conn_expr::{ConnectionExpr, ConnectionExprSource},
+  args_file,
+  assertions::{EnvAssertion, HostAssertion},
+  conn_expr::{ConnectionExpr, ConnectionExprSource, PortFileDiscovery},
   error::Error,
+  host_options::SshOptions,
+  route_cache::DEFAULT_ROUTE_CACHE_TTL,
+  socket::{ConnectOptions, DEFAULT_CONNECT_TIMEOUT},
   version::{Version, VersionRange},
 };
 
@@ -35,14 +40,30 @@ pub use self::tristate::Tristate;
 pub struct Args {
   pub version_range: Option<VersionRange>,
   pub conn_expr_src: ConnectionExprSource,
+  pub which_port_file: bool,
+  pub host_assertions: Vec<HostAssertion>,
+  pub ssh_options: SshOptions,
+  pub close_ssh_masters: bool,
+  pub connect_options: ConnectOptions,
   pub stdin_from: Option<IoArg>,
   pub stdout_to: Option<IoArg>,
   pub stderr_to: Option<IoArg>,
   pub results_to: Option<IoArg>,
+  pub exprs_to: Option<IoArg>,
   pub source_args: Vec<SourceArg>,
+  /// The script run in the shebang mode
+  pub script: Option<SourceArg>,
   pub template_args: Vec<TemplateArg>,
+  pub env_prefix: String,
   pub pretty: Tristate,
   pub color: Tristate,
+  pub verbosity: u8,
+  pub trace_wire: bool,
+  pub log_file: Option<path::PathBuf>,
+  pub record: Option<path::PathBuf>,
+  pub replay: Option<path::PathBuf>,
+  pub script_help: bool,
+  pub dry_run: bool,
 }
 
 impl Args {
@@ -52,6 +73,7 @@ impl Args {
     // Inject an "any" version range after the shebang mode flag (`-!`) in
     // certain cases.  This helps Clap to not confuse positional arguments with
     // the optional version assertion.
+    let mut script_help = false;
     if let Some(pos) =
       args_os.iter().position(|arg| *arg == ffi::OsStr::new("-!"))
     {
@@ -63,19 +85,24 @@ impl Args {
       {
         args_os.insert(pos + 1, "..".into());
       }
+      script_help = take_script_help(&mut args_os, pos + 2);
     }
 
-    Self::try_from(Cli::parse_from(args_os))
+    let matches = Cli::command().get_matches_from(args_os);
+    Self::from_matches(&matches).map(|args| Self {
+      script_help,
+      ..args
+    })
   }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum IoArg {
   Pipe,
   File(path::PathBuf),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum SourceArg {
   Pipe,
   Expr(String),
@@ -97,17 +124,23 @@ impl From<IoArg> for SourceArg {
   }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct TemplateArg {
   pub pos: Option<usize>,
   pub name: Option<Rc<str>>,
   pub value: Rc<str>,
+  /// Whether the value is Clojure data (from an arguments file) rather than
+  /// text to be interpreted
+  pub literal: bool,
 }
 
-impl TryFrom<Cli> for Args {
-  type Error = Error;
-
-  fn try_from(cli: Cli) -> Result<Self, Self::Error> {
+impl Args {
+  /// Builds the arguments from what Clap parsed.  Clap also knows where each
+  /// expression and file was given; we need that for evaluating them in the
+  /// same order.
+  fn from_matches(matches: &clap::ArgMatches) -> Result<Self, Error> {
+    let cli = Cli::from_arg_matches(matches).unwrap_or_else(|e| e.exit());
+    let started = time::Instant::now();
     let (shebang_mode, assert_version) = match cli.shebang_guard {
       Some(version) => (true, version),
       None => (false, None),
@@ -115,45 +148,55 @@ impl TryFrom<Cli> for Args {
 
     let mut pos_arg_it = cli.pos_args.iter();
 
-    // XXX(soija) I don't like the how expressions and files are mutually
-    //            exclusive. Figure out a way to lift this restriction SO that
-    //            the relative order of expressions and files is honored.  That
-    //            is, `-e a -f b -e c` should be sourced in order `a`, `b`,
-    //            and `c`.
+    // The expressions and files are evaluated in the order they are given
+    let mut ordered_sources = cli
+      .exprs
+      .iter()
+      .zip(indices_of(matches, "exprs"))
+      .map(|(e, ix)| Ok((ix, SourceArg::Expr(e.clone()))))
+      .chain(cli.files.iter().zip(indices_of(matches, "files")).map(
+        |(f, ix)| {
+          IoArg::parse_from_path_or_pipe(f)
+            .map(|io| (ix, SourceArg::from(io)))
+            .map_err(|_| Error::BadSourceFile)
+        },
+      ))
+      .collect::<Result<Vec<_>, _>>()?;
 
-    let source_args = if shebang_mode {
+    let script = if shebang_mode {
       //
       // When the shebang guard is given the first positional argument is always
-      // interpreted as the (sole) source.
+      // interpreted as the script.  The expressions and files given before and
+      // after it are evaluated before and after the script, respectively.
       //
       let pos_arg = pos_arg_it.next().ok_or(Error::NoInput)?;
       let src_arg = IoArg::parse_from_path(pos_arg)
         .map(SourceArg::from)
         .map_err(|_| Error::BadSourceFile)?;
-      vec![src_arg]
-    } else if !cli.exprs.is_empty() {
-      cli
-        .exprs
-        .iter()
-        .map(|e| SourceArg::Expr(e.clone()))
-        .collect()
-    } else if !cli.files.is_empty() {
-      cli
-        .files
-        .iter()
-        .map(|f| {
-          IoArg::parse_from_path_or_pipe(f)
-            .map(SourceArg::from)
-            .map_err(|_| Error::BadSourceFile)
-        })
-        .collect::<Result<_, _>>()?
+      let script_index = indices_of(matches, "pos_args")
+        .first()
+        .copied()
+        .unwrap_or_default();
+      ordered_sources.push((script_index, src_arg.clone()));
+      Some(src_arg)
+    } else {
+      None
+    };
+    ordered_sources.sort_by_key(|(ix, _)| *ix);
+
+    let source_args = if !ordered_sources.is_empty() {
+      ordered_sources.into_iter().map(|(_, s)| s).collect()
     } else if !io::stdin().is_terminal() {
       vec![SourceArg::Pipe]
     } else if let Some(f) = pos_arg_it.next() {
       vec![IoArg::parse_from_path_or_pipe(f)
         .map(SourceArg::from)
         .map_err(|_| Error::BadSourceFile)?]
-    } else if cli.wait_port_file.is_some() {
+    } else if cli.wait_port_file.is_some()
+      || cli.wait_port.is_some()
+      || cli.close_ssh_masters
+      || cli.which_port_file
+    {
       vec![]
     } else {
       return Err(Error::NoInput);
@@ -189,7 +232,12 @@ impl TryFrom<Cli> for Args {
       return Err(Error::StdInConflict);
     }
 
-    let args = cli
+    // The arguments files go first so that the command line wins
+    let mut args = Vec::new();
+    for path in cli.args_files.iter() {
+      args.extend(args_file::load(path)?);
+    }
+    for arg in cli
       .args
       .iter()
       .map(|arg| TemplateArg::parse(None, arg))
@@ -198,17 +246,40 @@ impl TryFrom<Cli> for Args {
           .enumerate()
           .map(|(i, arg)| TemplateArg::parse(Some(i), arg)),
       )
-      .collect::<Result<_, _>>()?;
+    {
+      args.push(arg?);
+    }
 
-    let conn_expr_src = if let Some(ref h) = cli.port {
-      h.into()
-    } else {
-      ConnectionExprSource::PortFile {
-        path: cli.port_file.clone(),
-        wait_for: cli.wait_port_file.map(time::Duration::from_secs),
+    let conn_expr_src = {
+      let mut discovery = PortFileDiscovery {
+        prefer_first: cli.first_port_file,
+        ..Default::default()
+      };
+      if !cli.port_file_names.is_empty() {
+        discovery.names = cli.port_file_names.clone();
       }
+      ConnectionExprSource::select(
+        cli.port.as_ref(),
+        cli.port_file.as_deref(),
+        discovery,
+        cli.wait_port_file.map(time::Duration::from_secs),
+        |name| env::var(name).ok(),
+      )?
     };
 
+    let host_assertions = cli
+      .assert_hostname
+      .iter()
+      .map(|h| HostAssertion::Hostname(h.clone()))
+      .chain(cli.assert_env.iter().map(|e| e.0.clone()))
+      .chain(
+        cli
+          .assert_form
+          .iter()
+          .map(|f| HostAssertion::Form(f.clone())),
+      )
+      .collect();
+
     fn tristate(on: bool, off: bool) -> Tristate {
       use Tristate::*;
       match (on, off) {
@@ -221,6 +292,37 @@ impl TryFrom<Cli> for Args {
     Ok(Self {
       version_range: assert_version,
       conn_expr_src,
+      which_port_file: cli.which_port_file,
+      host_assertions,
+      ssh_options: SshOptions {
+        control_master: match tristate(
+          cli.ssh_control_master,
+          cli.no_ssh_control_master,
+        ) {
+          Tristate::Auto => None,
+          t => Some(t.to_bool(false)),
+        },
+        control_persist: cli.ssh_control_persist,
+        ..Default::default()
+      },
+      close_ssh_masters: cli.close_ssh_masters,
+      connect_options: ConnectOptions {
+        timeout: cli
+          .connect_timeout
+          .map(time::Duration::from_secs)
+          .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+        // The time spent waiting for the port file counts too
+        retry_until: cli
+          .connect_retry
+          .max(cli.wait_port)
+          .map(|secs| started + time::Duration::from_secs(secs)),
+        handshake: cli.wait_port.is_some(),
+        route_cache_ttl: match cli.route_cache_ttl {
+          Some(0) => None,
+          Some(secs) => Some(time::Duration::from_secs(secs)),
+          None => Some(DEFAULT_ROUTE_CACHE_TTL),
+        },
+      },
       stdin_from,
       stdout_to: if cli.no_stdout {
         None
@@ -255,10 +357,26 @@ impl TryFrom<Cli> for Args {
             .unwrap_or(IoArg::Pipe),
         )
       },
+      // The dry run shows the code on stdout unless told otherwise
+      exprs_to: match cli.exprs_to {
+        Some(ref p) if p.as_os_str() == "-" => Some(IoArg::Pipe),
+        Some(ref p) => Some(IoArg::from_path(p)),
+        None if cli.dry_run => Some(IoArg::Pipe),
+        None => None,
+      },
       source_args,
       template_args: args,
+      env_prefix: cli.env_prefix,
       pretty: tristate(cli.pretty, cli.no_pretty),
       color: tristate(cli.color, cli.no_color),
+      verbosity: cli.verbose,
+      trace_wire: cli.trace_wire,
+      log_file: cli.log_file,
+      record: cli.record,
+      replay: cli.replay,
+      script,
+      script_help: false,
+      dry_run: cli.dry_run,
     })
   }
 }
@@ -273,13 +391,15 @@ impl TemplateArg {
         Ok(Self {
           pos,
           name: Some(name.to_string().into()),
-          value: value.to_string().into(),
+          value: read_value(value)?.into(),
+          literal: false,
         })
       } else if pos.is_some() {
         Ok(Self {
           pos,
           name: None,
           value: s.to_string().into(),
+          literal: false,
         })
       } else {
         // non-positional arg must have a name
@@ -292,6 +412,21 @@ impl TemplateArg {
   }
 }
 
+/// Reads the value from the file when given as `@file`; `@@` escapes a
+/// leading `@`.
+fn read_value(value: &str) -> Result<String, Error> {
+  match value.strip_prefix('@') {
+    Some(escaped) if escaped.starts_with('@') => Ok(escaped.to_owned()),
+    Some(file) => {
+      let content = fs::read_to_string(file)
+        .map_err(|_| Error::CannotReadFile(file.to_owned()))?;
+      let content = content.strip_suffix('\n').unwrap_or(&content);
+      Ok(content.strip_suffix('\r').unwrap_or(content).to_owned())
+    }
+    None => Ok(value.to_owned()),
+  }
+}
+
 impl IoArg {
   fn from_path(path: impl AsRef<path::Path>) -> Self {
     IoArg::File(path.as_ref().to_owned())
@@ -437,26 +572,77 @@ struct Cli {
   #[arg(long, value_name = "FILE")]
   port_file: Option<path::PathBuf>,
 
+  /// Look for port files NAME,... in the order of priority
+  #[arg(
+    long,
+    value_name = "NAME,...",
+    value_delimiter = ',',
+    conflicts_with = "port_file"
+  )]
+  port_file_names: Vec<path::PathBuf>,
+
+  /// Use the highest priority port file even if others disagree
+  #[arg(long, conflicts_with = "port_file")]
+  first_port_file: bool,
+
+  /// Show which port file would be used and why, then exit
+  #[arg(long)]
+  which_port_file: bool,
+
+  /// Abort unless the server's hostname is NAME
+  #[arg(long, value_name = "NAME")]
+  assert_hostname: Vec<String>,
+
+  /// Abort unless the server's environment variable VAR is VALUE
+  #[arg(long, value_name = "VAR=VALUE")]
+  assert_env: Vec<EnvAssertion>,
+
+  /// Abort unless FORM evaluates truthy on the server
+  #[arg(long, value_name = "FORM")]
+  assert_form: Vec<String>,
+
+  /// Reuse SSH connections through a control master
+  #[arg(long, conflicts_with = "no_ssh_control_master")]
+  ssh_control_master: bool,
+
+  /// Do not reuse SSH connections
+  #[arg(long, conflicts_with = "ssh_control_master")]
+  no_ssh_control_master: bool,
+
+  /// Keep idle SSH control masters around for SECONDS
+  #[arg(long, value_name = "SECONDS")]
+  ssh_control_persist: Option<u64>,
+
+  /// Close all SSH control masters and exit
+  #[arg(long)]
+  close_ssh_masters: bool,
+
+  /// Give up a connection attempt after SECONDS
+  #[arg(long, value_name = "SECONDS", value_parser = parse_nonzero_secs)]
+  connect_timeout: Option<u64>,
+
+  /// Keep retrying the connection for SECONDS
+  #[arg(long, value_name = "SECONDS")]
+  connect_retry: Option<u64>,
+
+  /// Trust the cached SSH route for SECONDS (0 disables the cache)
+  #[arg(long, value_name = "SECONDS")]
+  route_cache_ttl: Option<u64>,
+
+  /// Wait SECONDS for the server to answer
+  #[arg(long, value_name = "SECONDS")]
+  wait_port: Option<u64>,
+
   /// Evaluate within NAMESPACE
   #[arg(long, visible_alias = "namespace", value_name = "NAMESPACE")]
   ns: Option<String>,
 
   /// Evaluate EXPRESSION
-  #[arg(
-    long = "expr",
-    short,
-    value_name = "EXPRESSION",
-    conflicts_with = "files"
-  )]
+  #[arg(long = "expr", short, value_name = "EXPRESSION")]
   exprs: Vec<String>,
 
   /// Evaluate FILE
-  #[arg(
-    long = "file",
-    short = 'f',
-    value_name = "FILE",
-    conflicts_with = "exprs"
-  )]
+  #[arg(long = "file", short = 'f', value_name = "FILE")]
   files: Vec<ffi::OsString>,
 
   /// Send FILE to server's stdin
@@ -499,10 +685,26 @@ struct Cli {
   )]
   no_results: bool,
 
-  /// Set template argument NAME to VALUE
+  /// Echo the code sent to the server to FILE (- for stdout)
+  #[arg(long, value_name = "FILE")]
+  exprs_to: Option<path::PathBuf>,
+
+  /// Show what would be sent to the server without connecting to it
+  #[arg(long)]
+  dry_run: bool,
+
+  /// Set template argument NAME to VALUE (or to the content of @FILE)
   #[arg(long = "arg", short = 'a', value_name = "NAME=VALUE")]
   args: Vec<String>,
 
+  /// Read template arguments from FILE (EDN, JSON, or TOML)
+  #[arg(long = "args-file", value_name = "FILE")]
+  args_files: Vec<path::PathBuf>,
+
+  /// Read template arguments from environment variables PREFIX<NAME>
+  #[arg(long, value_name = "PREFIX", default_value = DEFAULT_ENV_PREFIX)]
+  env_prefix: String,
+
   /// Positional arguments
   #[arg(value_name = "ARG")]
   pos_args: Vec<ffi::OsString>,
@@ -512,11 +714,10 @@ struct Cli {
     short = '!',
     value_name = "VERSION_EXPR",
     value_parser = parse_version_range,
-    conflicts_with_all = &["exprs", "files"],
   )]
   shebang_guard: Option<Option<VersionRange>>,
 
-  /// Wait .nrepl-port file to appear for SECONDS
+  /// Wait port file to appear for SECONDS
   #[arg(long = "wait-port-file", value_name = "SECONDS")]
   wait_port_file: Option<u64>,
 
@@ -536,6 +737,30 @@ struct Cli {
   #[arg(long, conflicts_with = "pretty")]
   no_pretty: bool,
 
+  /// Log what happens while connecting (-vv for details)
+  #[arg(long, short, action = clap::ArgAction::Count)]
+  verbose: u8,
+
+  /// Log the nREPL messages sent and received
+  #[arg(long)]
+  trace_wire: bool,
+
+  /// Write the log to FILE instead of stderr
+  #[arg(long, value_name = "FILE")]
+  log_file: Option<path::PathBuf>,
+
+  /// Record the nREPL session to FILE
+  #[arg(long, value_name = "FILE")]
+  record: Option<path::PathBuf>,
+
+  /// Replay the session recorded in FILE instead of connecting to a server
+  #[arg(
+    long,
+    value_name = "FILE",
+    conflicts_with_all = &["port", "port_file", "wait_port_file", "wait_port"],
+  )]
+  replay: Option<path::PathBuf>,
+
   /// Enforce colored output
   #[arg(long, conflicts_with = "no_color")]
   color: bool,
@@ -545,6 +770,29 @@ struct Cli {
   no_color: bool,
 }
 
+/// The prefix of the environment variables giving template arguments
+const DEFAULT_ENV_PREFIX: &str = "NR_ARG_";
+
+/// Removes the help flag right after the script, if any.  It asks for the help
+/// of the script rather than that of ours.  The flags further on, as well as
+/// the ones after `--`, are the script's own arguments.
+fn take_script_help(args_os: &mut Vec<ffi::OsString>, script: usize) -> bool {
+  match args_os.get(script + 1) {
+    Some(arg) if *arg == "--help" || *arg == "-h" => {
+      args_os.remove(script + 1);
+      true
+    }
+    _ => false,
+  }
+}
+
+fn indices_of(matches: &clap::ArgMatches, id: &str) -> Vec<usize> {
+  matches
+    .indices_of(id)
+    .map(Iterator::collect)
+    .unwrap_or_default()
+}
+
 fn parse_version_range(s: &str) -> Result<VersionRange, &'static str> {
   if let Ok(start) = s.parse::<Version>() {
     let end = start.next_breaking();
@@ -560,6 +808,51 @@ fn parse_version_range(s: &str) -> Result<VersionRange, &'static str> {
   }
 }
 
+fn parse_nonzero_secs(s: &str) -> Result<u64, &'static str> {
+  match s.parse() {
+    Ok(0) | Err(_) => Err("expected a positive number of seconds"),
+    Ok(secs) => Ok(secs),
+  }
+}
+
 fn not_implemented<T>(_: &str) -> Result<T, &'static str> {
   Err("this feature has not been implemented yet, sorry")
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn script_help_only_right_after_script() {
+    let take = |args: &[&str]| {
+      let mut args_os = args.iter().map(Into::into).collect::<Vec<_>>();
+      let help = take_script_help(&mut args_os, 3);
+      (help, args_os.len())
+    };
+    assert_eq!(take(&["nr", "-!", "..", "s.clj", "-h"]), (true, 4));
+    assert_eq!(take(&["nr", "-!", "..", "s.clj", "--help", "x"]), (true, 5));
+    assert_eq!(take(&["nr", "-!", "..", "s.clj", "foo", "-h"]), (false, 6));
+    assert_eq!(take(&["nr", "-!", "..", "s.clj", "--", "-h"]), (false, 6));
+    assert_eq!(take(&["nr", "-!", "..", "s.clj"]), (false, 4));
+  }
+
+  #[test]
+  fn sources_in_command_line_order() {
+    let sources = |args: &[&str]| {
+      let matches = Cli::command().get_matches_from(args);
+      Args::from_matches(&matches).unwrap().source_args
+    };
+    let expr = |s: &str| SourceArg::Expr(s.to_owned());
+    let file =
+      |s: &str| SourceArg::File(path::Path::new(s).canonicalize().unwrap());
+    assert_eq!(
+      sources(&["nr", "-e", "1", "-f", "Cargo.toml", "-e", "2", "-f", "-"]),
+      [expr("1"), file("Cargo.toml"), expr("2"), SourceArg::Pipe]
+    );
+    assert_eq!(
+      sources(&["nr", "-f", "Cargo.toml", "-e", "1"]),
+      [file("Cargo.toml"), expr("1")]
+    );
+  }
+}
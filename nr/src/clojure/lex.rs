@@ -222,6 +222,90 @@ pub enum Lexeme<'a> {
   Residual(Pair<'a>),
 }
 
+impl<'a> Lexeme<'a> {
+  /// Returns the form to which the lexeme belongs, if any.
+  pub fn form_ix(&self) -> Option<FormIx> {
+    match self {
+      L::Meta { form_ix, .. }
+      | L::Discard { form_ix, .. }
+      | L::Quote { form_ix, .. }
+      | L::VarQuote { form_ix, .. }
+      | L::Synquote { form_ix, .. }
+      | L::Unquote { form_ix, .. }
+      | L::SplicingUnquote { form_ix, .. }
+      | L::Nil { form_ix, .. }
+      | L::Boolean { form_ix, .. }
+      | L::Numeric { form_ix, .. }
+      | L::Char { form_ix, .. }
+      | L::String { form_ix, .. }
+      | L::Regex { form_ix, .. }
+      | L::SymbolicValuePrefix { form_ix, .. }
+      | L::SymbolicValue { form_ix, .. }
+      | L::Symbol { form_ix, .. }
+      | L::Tag { form_ix, .. }
+      | L::Keyword { form_ix, .. }
+      | L::StartList { form_ix, .. }
+      | L::EndList { form_ix, .. }
+      | L::StartVector { form_ix, .. }
+      | L::EndVector { form_ix, .. }
+      | L::StartSet { form_ix, .. }
+      | L::EndSet { form_ix, .. }
+      | L::MapQualifier { form_ix, .. }
+      | L::StartMap { form_ix, .. }
+      | L::EndMap { form_ix, .. }
+      | L::StartAnonymousFn { form_ix, .. }
+      | L::EndAnonymousFn { form_ix, .. }
+      | L::ReaderConditionalPrefix { form_ix, .. }
+      | L::StartReaderConditional { form_ix, .. }
+      | L::EndReaderConditional { form_ix, .. }
+      | L::TaggedLiteral { form_ix, .. } => Some(*form_ix),
+      L::Whitespace { .. } | L::Comment { .. } | L::Residual(_) => None,
+    }
+  }
+
+  /// Returns the part of the input the lexeme was lexed from.
+  pub fn source(&self) -> &'a str {
+    match self {
+      L::Whitespace { source, .. }
+      | L::Comment { source, .. }
+      | L::Meta { source, .. }
+      | L::Discard { source, .. }
+      | L::Quote { source, .. }
+      | L::VarQuote { source, .. }
+      | L::Synquote { source, .. }
+      | L::Unquote { source, .. }
+      | L::SplicingUnquote { source, .. }
+      | L::Nil { source, .. }
+      | L::Boolean { source, .. }
+      | L::Numeric { source, .. }
+      | L::Char { source, .. }
+      | L::String { source, .. }
+      | L::Regex { source, .. }
+      | L::SymbolicValuePrefix { source, .. }
+      | L::SymbolicValue { source, .. }
+      | L::Symbol { source, .. }
+      | L::Tag { source, .. }
+      | L::Keyword { source, .. }
+      | L::StartList { source, .. }
+      | L::EndList { source, .. }
+      | L::StartVector { source, .. }
+      | L::EndVector { source, .. }
+      | L::StartSet { source, .. }
+      | L::EndSet { source, .. }
+      | L::MapQualifier { source, .. }
+      | L::StartMap { source, .. }
+      | L::EndMap { source, .. }
+      | L::StartAnonymousFn { source, .. }
+      | L::EndAnonymousFn { source, .. }
+      | L::ReaderConditionalPrefix { source, .. }
+      | L::StartReaderConditional { source, .. }
+      | L::EndReaderConditional { source, .. }
+      | L::TaggedLiteral { source, .. } => source,
+      L::Residual(pair) => pair.as_str(),
+    }
+  }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum CharSyntax {
   Name,
@@ -500,6 +584,12 @@ impl<'a> Helper<'a> {
           .unwrap(),
           source,
         }),
+        R::char_simple => self.push(L::Char {
+          form_ix,
+          syntax: CharSyntax::Simple,
+          value: child.as_str().chars().next().unwrap(),
+          source,
+        }),
         _ => self.push(L::Residual(child)),
       }
     }
@@ -698,12 +788,10 @@ impl<'a> Helper<'a> {
   }
 
   fn regex(&mut self, parent: Pair<'a>, form_ix: FormIx) {
+    let source = parent.as_str();
     for child in parent.into_inner() {
       match child.as_rule() {
-        R::regex_content => self.push(L::Regex {
-          form_ix,
-          source: child.as_str(),
-        }),
+        R::regex_content => self.push(L::Regex { form_ix, source }),
         _ => self.push(L::Residual(child)),
       }
     }
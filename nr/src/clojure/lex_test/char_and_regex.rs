@@ -0,0 +1,53 @@
+// clojure/lex_test/char_and_regex.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+use super::*;
+
+#[test]
+fn simple_char() {
+  assert_lexemes! {
+    "\\a",
+    Lexeme::Char {
+      syntax: CharSyntax::Simple,
+      value: 'a',
+      source: "\\a",
+      ..
+    }
+  }
+}
+
+#[test]
+fn named_char() {
+  assert_lexemes! {
+    "\\newline",
+    Lexeme::Char {
+      syntax: CharSyntax::Name,
+      value: '\n',
+      source: "\\newline",
+      ..
+    }
+  }
+}
+
+#[test]
+fn regex() {
+  assert_lexemes! {
+    r#"#"a\"b""#,
+    Lexeme::Regex {
+      source: r#"#"a\"b""#,
+      ..
+    }
+  }
+}
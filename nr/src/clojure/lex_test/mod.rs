@@ -13,6 +13,7 @@
 // License for the specific language governing permissions and limitations under
 // the License.
 
+mod char_and_regex;
 mod discard_and_meta;
 mod keyword;
 
@@ -0,0 +1,69 @@
+// clojure/literal.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Helpers for producing Clojure literals out of Rust values and back.
+
+use super::lex::StringFragment;
+
+/// Renders the string as a Clojure string literal.
+///
+/// The escaping matches that of Clojure's own printer (`pr-str`) so that the
+/// result can be compared against the values returned by the server.
+pub fn string(s: &str) -> String {
+  let mut literal = String::with_capacity(s.len() + 2);
+  literal.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => literal.push_str("\\\""),
+      '\\' => literal.push_str("\\\\"),
+      '\n' => literal.push_str("\\n"),
+      '\t' => literal.push_str("\\t"),
+      '\r' => literal.push_str("\\r"),
+      '\u{0C}' => literal.push_str("\\f"),
+      '\u{08}' => literal.push_str("\\b"),
+      c => literal.push(c),
+    }
+  }
+  literal.push('"');
+  literal
+}
+
+/// Returns the value of the string literal given as its lexed fragments.
+pub fn string_value(fragments: &[StringFragment]) -> String {
+  fragments
+    .iter()
+    .map(|fragment| match *fragment {
+      StringFragment::Unescaped { value } => value.to_owned(),
+      StringFragment::Escaped { code } => {
+        char::from_u32(code).unwrap_or('\u{FFFD}').to_string()
+      }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn string_literals() {
+    assert_eq!(string(""), r#""""#);
+    assert_eq!(string("foo"), r#""foo""#);
+    assert_eq!(string(r#"say "hi""#), r#""say \"hi\"""#);
+    assert_eq!(string("a\\b"), r#""a\\b""#);
+    assert_eq!(string("a\nb\tc"), r#""a\nb\tc""#);
+    assert_eq!(string("äö"), r#""äö""#);
+  }
+}
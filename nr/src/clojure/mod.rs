@@ -14,6 +14,7 @@
 // the License.
 
 pub mod lex;
+pub mod literal;
 pub mod result_ir;
 
 mod pest_grammar;
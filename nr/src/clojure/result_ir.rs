@@ -98,7 +98,9 @@ pub fn build<'a>(lexemes: &[Lexeme<'a>]) -> Result<Value<'a>, BuildError> {
 
   for lexeme in lexemes {
     let mut composite_ready = match lexeme {
-      Whitespace { .. } | SymbolicValuePrefix { .. } => false, // ignore
+      Whitespace { .. } | Comment { .. } | SymbolicValuePrefix { .. } => {
+        false // ignore
+      }
       Nil { .. } => b.add_to_composite(Value::Nil)?,
       Boolean { value, .. } => {
         b.add_to_composite(Value::Boolean { value: *value })?
@@ -32,6 +32,17 @@ impl fmt::Display for Addr {
   }
 }
 
+impl Addr {
+  /// Formats the address as it is written in a connection expression, that
+  /// is, an IPv6 address goes in brackets.
+  pub fn to_expr_string(&self) -> String {
+    match self {
+      Addr::IP(net::IpAddr::V6(ip)) => format!("[{}]", ip),
+      addr => addr.to_string(),
+    }
+  }
+}
+
 impl<'a> TryFrom<Pair<'a, Rule>> for Addr {
   type Error = ConversionError;
 
@@ -13,7 +13,7 @@
 // License for the specific language governing permissions and limitations under
 // the License.
 
-use std::str;
+use std::{fmt, path::PathBuf, str};
 
 use super::{
   addr::Addr,
@@ -24,7 +24,11 @@ use super::{
 #[derive(Clone, Debug, PartialEq)]
 pub enum ConnectionExpr {
   RouteExpr(RouteExpr),
+  CommandExpr(CommandExpr),
   HostKey(String),
+  /// Read the connection expression from the port file, e.g.
+  /// `@.shadow-cljs/nrepl.port`
+  PortFile(PathBuf),
 }
 
 impl ConnectionExpr {
@@ -35,6 +39,20 @@ impl ConnectionExpr {
       None
     }
   }
+
+  pub fn try_as_host_key(&self) -> Option<&str> {
+    if let ConnectionExpr::HostKey(ref k) = *self {
+      Some(k)
+    } else {
+      None
+    }
+  }
+}
+
+impl From<CommandExpr> for ConnectionExpr {
+  fn from(command_expr: CommandExpr) -> Self {
+    ConnectionExpr::CommandExpr(command_expr)
+  }
 }
 
 impl From<RouteExpr> for ConnectionExpr {
@@ -47,9 +65,29 @@ impl From<RouteExpr> for ConnectionExpr {
 pub struct RouteExpr {
   pub ports: PortSet,
   pub addr: Option<Addr>,
-  pub tunnel: Option<TunnelExpr>,
+  /// The SSH hops in the order they are traversed; empty if not tunneled.
+  pub tunnel: Vec<TunnelExpr>,
+}
+
+impl RouteExpr {
+  pub fn is_tunneled(&self) -> bool {
+    !self.tunnel.is_empty()
+  }
 }
 
+impl fmt::Display for RouteExpr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for hop in self.tunnel.iter() {
+      write!(f, "{}:", hop)?;
+    }
+    if let Some(ref addr) = self.addr {
+      write!(f, "{}:", addr.to_expr_string())?;
+    }
+    write!(f, "{}", self.ports)
+  }
+}
+
+/// A single SSH hop of a tunnel
 #[derive(Clone, Debug, PartialEq)]
 pub struct TunnelExpr {
   pub user: Option<String>,
@@ -57,6 +95,45 @@ pub struct TunnelExpr {
   pub ports: Option<PortSet>,
 }
 
+impl fmt::Display for TunnelExpr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if let Some(ref user) = self.user {
+      write!(f, "{}@", user)?;
+    }
+    f.write_str(&self.addr.to_expr_string())?;
+    if let Some(ref ports) = self.ports {
+      write!(f, ":{}", ports)?;
+    }
+    Ok(())
+  }
+}
+
+/// A connection through the stdio of a command run locally, e.g.
+/// `kubectl://my-pod:7888`
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommandExpr {
+  pub scheme: CommandScheme,
+  /// The pod, container, etc. the command connects to
+  pub target: String,
+  pub ports: PortSet,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandScheme {
+  Kubectl,
+  Docker,
+}
+
+impl CommandScheme {
+  /// Returns the command template used for connecting to the target.
+  pub fn template(self) -> &'static str {
+    match self {
+      CommandScheme::Kubectl => "kubectl exec -i {host} -- nc localhost {port}",
+      CommandScheme::Docker => "docker exec -i {host} nc localhost {port}",
+    }
+  }
+}
+
 impl str::FromStr for ConnectionExpr {
   type Err = ParseError;
 
@@ -69,6 +146,17 @@ impl str::FromStr for ConnectionExpr {
       .next()
       .expect("grammar guarantees inner specific host expression");
     match top_pair.as_rule() {
+      Rule::port_file_expr => Ok(ConnectionExpr::PortFile(
+        top_pair
+          .into_inner()
+          .next()
+          .expect("grammar guarantees a port file path")
+          .as_str()
+          .into(),
+      )),
+      Rule::command_connection_expr => {
+        command_expr_from_pairs(top_pair.into_inner()).map(|e| e.into())
+      }
       Rule::local_connection_expr => {
         connection_expr_from_local_connection_expr_pair(top_pair.into_inner())
           .map(|e| e.into())
@@ -87,13 +175,43 @@ impl str::FromStr for ConnectionExpr {
         Ok(ConnectionExpr::HostKey(top_pair.as_str().to_string()))
       }
       _ => unreachable!(
-        r#"grammar guarantees a local, remote, or tunneled route
-                   expression to a remote host, or a host key reference"#
+        r#"grammar guarantees a local, remote, tunneled, or command route
+                   expression to a remote host, a host key reference, or a
+                   port file reference"#
       ),
     }
   }
 }
 
+fn command_expr_from_pairs(
+  mut pairs: Pairs<Rule>,
+) -> Result<CommandExpr, ParseError> {
+  let scheme = match pairs
+    .next()
+    .expect("grammar guarantees a command scheme")
+    .as_str()
+  {
+    "kubectl" => CommandScheme::Kubectl,
+    "docker" => CommandScheme::Docker,
+    _ => unreachable!("grammar guarantees a known command scheme"),
+  };
+  let target = pairs
+    .next()
+    .expect("grammar guarantees a command target")
+    .as_str()
+    .to_owned();
+  let ports = pairs
+    .next()
+    .expect("grammar guarantees a port set")
+    .try_into()
+    .map_err(|_| ParseError)?;
+  Ok(CommandExpr {
+    scheme,
+    target,
+    ports,
+  })
+}
+
 fn connection_expr_from_local_connection_expr_pair(
   mut pairs: Pairs<Rule>,
 ) -> Result<RouteExpr, ParseError> {
@@ -105,7 +223,7 @@ fn connection_expr_from_local_connection_expr_pair(
       // grammar does not limit the port to u16
       .map_err(|_| ParseError)?,
     addr: None,
-    tunnel: None,
+    tunnel: vec![],
   })
 }
 
@@ -130,20 +248,23 @@ fn connection_expr_from_remote_connection_expr_pair(
 fn connection_expr_from_tunneled_connection_expr_pair(
   pairs: Pairs<Rule>,
 ) -> Result<RouteExpr, ParseError> {
-  let (tunnel, mut pairs) = tunnel_from_pairs(pairs)?;
-  let mut connection_expr = connection_expr_from_remote_connection_expr_pair(
-    pairs
-      .next()
-      .expect("grammar guarantees a remote port expression")
-      .into_inner(),
-  )?;
-  connection_expr.tunnel = Some(tunnel);
-  Ok(connection_expr)
+  let mut tunnel = vec![];
+  for pair in pairs {
+    match pair.as_rule() {
+      Rule::tunnel_hop => tunnel.push(tunnel_from_pairs(pair.into_inner())?),
+      Rule::remote_connection_expr => {
+        let mut connection_expr =
+          connection_expr_from_remote_connection_expr_pair(pair.into_inner())?;
+        connection_expr.tunnel = tunnel;
+        return Ok(connection_expr);
+      }
+      _ => unreachable!("grammar guarantees tunnel hops or remote expression"),
+    }
+  }
+  unreachable!("grammar guarantees a remote port expression")
 }
 
-fn tunnel_from_pairs(
-  mut pairs: Pairs<Rule>,
-) -> Result<(TunnelExpr, Pairs<Rule>), ParseError> {
+fn tunnel_from_pairs(mut pairs: Pairs<Rule>) -> Result<TunnelExpr, ParseError> {
   let mut next = pairs.next().expect("grammar guarantees a user or address");
   let user = if matches!(next.as_rule(), Rule::user) {
     let s = next.as_str().to_owned();
@@ -153,14 +274,11 @@ fn tunnel_from_pairs(
     None
   };
   match next.as_rule() {
-    Rule::addr => Ok((
-      TunnelExpr {
-        user,
-        addr: next.try_into().expect("grammar guarantess addr is legal"),
-        ports: None,
-      },
-      pairs,
-    )),
+    Rule::addr => Ok(TunnelExpr {
+      user,
+      addr: next.try_into().expect("grammar guarantess addr is legal"),
+      ports: None,
+    }),
     Rule::addr_and_port => {
       let mut inner = next.into_inner();
       let addr = inner
@@ -173,14 +291,11 @@ fn tunnel_from_pairs(
         .expect("port_set by grammar")
         .try_into()
         .map_err(|_| ParseError)?;
-      Ok((
-        TunnelExpr {
-          user,
-          addr,
-          ports: Some(ports),
-        },
-        pairs,
-      ))
+      Ok(TunnelExpr {
+        user,
+        addr,
+        ports: Some(ports),
+      })
     }
     _ => unreachable!("grammar guarantees addr or addr_and_port"),
   }
@@ -208,6 +323,7 @@ mod test {
     Addr::IP(net::Ipv4Addr::new(a, b, c, d).into())
   }
 
+  #[allow(clippy::too_many_arguments)]
   fn ip6(
     a: u16,
     b: u16,
@@ -231,7 +347,7 @@ mod test {
       Ok(ConnectionExpr::RouteExpr(RouteExpr {
         ports: ps(ports),
         addr: None,
-        tunnel: None,
+        tunnel: vec![],
       }))
     };
     assert_eq!("1".parse(), mk(&[1]));
@@ -246,7 +362,7 @@ mod test {
       Ok(ConnectionExpr::RouteExpr(RouteExpr {
         ports: ps(ports),
         addr: Some(addr),
-        tunnel: None,
+        tunnel: vec![],
       }))
     };
     assert_eq!("1.2.3.4:1,2-3".parse(), mk(ip4(1, 2, 3, 4), &[1, 2, 3]));
@@ -267,11 +383,11 @@ mod test {
       Ok(ConnectionExpr::RouteExpr(RouteExpr {
         ports: ps(server_ports),
         addr: Some(server_addr),
-        tunnel: Some(TunnelExpr {
+        tunnel: vec![TunnelExpr {
           user: tunnel_user.map(|s| s.to_owned()),
           addr: tunnel_addr,
           ports: maybe_ps(tunnel_ports),
-        }),
+        }],
       }))
     };
     assert_eq!(
@@ -340,10 +456,138 @@ mod test {
     );
   }
 
+  #[test]
+  fn multi_hop_tunneled_connection_expr_parsing() {
+    let hop = |user: Option<&str>, addr, ports: &[u16]| TunnelExpr {
+      user: user.map(|s| s.to_owned()),
+      addr,
+      ports: maybe_ps(ports),
+    };
+    let mk = |tunnel, server_addr, server_ports| {
+      Ok(ConnectionExpr::RouteExpr(RouteExpr {
+        ports: ps(server_ports),
+        addr: Some(server_addr),
+        tunnel,
+      }))
+    };
+    assert_eq!(
+      "bastion:jumphost:app-host:7888".parse(),
+      mk(
+        vec![
+          hop(None, dom("bastion"), &[]),
+          hop(None, dom("jumphost"), &[])
+        ],
+        dom("app-host"),
+        &[7888]
+      )
+    );
+    assert_eq!(
+      "a@bastion:2222:b@jumphost:app-host:7888".parse(),
+      mk(
+        vec![
+          hop(Some("a"), dom("bastion"), &[2222]),
+          hop(Some("b"), dom("jumphost"), &[])
+        ],
+        dom("app-host"),
+        &[7888]
+      )
+    );
+    assert_eq!(
+      "1.2.3.4:5:6.7.8.9:10.11.12.13:14:15.16.17.18:19".parse(),
+      mk(
+        vec![
+          hop(None, ip4(1, 2, 3, 4), &[5]),
+          hop(None, ip4(6, 7, 8, 9), &[]),
+          hop(None, ip4(10, 11, 12, 13), &[14])
+        ],
+        ip4(15, 16, 17, 18),
+        &[19]
+      )
+    );
+    assert_eq!(
+      "a:1,2:b:3-4:c:d:5".parse(),
+      mk(
+        vec![
+          hop(None, dom("a"), &[1, 2]),
+          hop(None, dom("b"), &[3, 4]),
+          hop(None, dom("c"), &[])
+        ],
+        dom("d"),
+        &[5]
+      )
+    );
+    assert_eq!(
+      "[::1]:22:[::2]:[::3]:1".parse(),
+      mk(
+        vec![
+          hop(None, ip6(0, 0, 0, 0, 0, 0, 0, 1), &[22]),
+          hop(None, ip6(0, 0, 0, 0, 0, 0, 0, 2), &[])
+        ],
+        ip6(0, 0, 0, 0, 0, 0, 0, 3),
+        &[1]
+      )
+    );
+    assert_eq!("a:b:c".parse::<ConnectionExpr>(), Err(ParseError));
+    assert_eq!("a:b:c:1:2".parse::<ConnectionExpr>(), Err(ParseError));
+  }
+
+  #[test]
+  fn command_connection_expr_parsing() {
+    let mk = |scheme, target: &str, ports| {
+      Ok(ConnectionExpr::CommandExpr(CommandExpr {
+        scheme,
+        target: target.to_owned(),
+        ports: ps(ports),
+      }))
+    };
+    assert_eq!(
+      "kubectl://my-app-7d9f8:7888".parse(),
+      mk(CommandScheme::Kubectl, "my-app-7d9f8", &[7888])
+    );
+    assert_eq!(
+      "kubectl://deployment/my-app:7888,7889".parse(),
+      mk(CommandScheme::Kubectl, "deployment/my-app", &[7888, 7889])
+    );
+    assert_eq!(
+      "docker://my_app_1:1-2".parse(),
+      mk(CommandScheme::Docker, "my_app_1", &[1, 2])
+    );
+    assert_eq!("docker://:1".parse::<ConnectionExpr>(), Err(ParseError));
+    assert_eq!("podman://app:1".parse::<ConnectionExpr>(), Err(ParseError));
+  }
+
   #[test]
   fn host_key_expr_parsing() {
     let mk = |key: &str| Ok(ConnectionExpr::HostKey(key.to_owned()));
     assert_eq!("x".parse(), mk("x"));
     assert_eq!("my_prod_host_1".parse(), mk("my_prod_host_1"));
   }
+
+  #[test]
+  fn route_expr_formatting() {
+    for s in [
+      "7888",
+      "localhost:7888,7889",
+      "[::1]:1,2,3",
+      "bastion:app:7888",
+      "alice@bastion:2222:bob@[::1]:22,23:10.0.0.1:7888",
+    ] {
+      match s.parse::<ConnectionExpr>() {
+        Ok(ConnectionExpr::RouteExpr(e)) => assert_eq!(e.to_string(), s),
+        other => panic!("unexpected {:?}", other),
+      }
+    }
+  }
+
+  #[test]
+  fn port_file_expr_parsing() {
+    let mk = |path: &str| Ok(ConnectionExpr::PortFile(path.into()));
+    assert_eq!("@.nrepl-port".parse(), mk(".nrepl-port"));
+    assert_eq!(
+      "@/work/app/.shadow-cljs/nrepl.port".parse(),
+      mk("/work/app/.shadow-cljs/nrepl.port")
+    );
+    assert_eq!("@my dir/port".parse(), mk("my dir/port"));
+    assert_eq!("@".parse::<ConnectionExpr>(), Err(ParseError));
+  }
 }
@@ -16,8 +16,10 @@
 pub mod parser;
 
 mod addr;
+mod port_files;
 mod port_set;
 mod resolution;
+mod watch;
 
 // XXX(soija) Okay, "deinception" this
 #[allow(clippy::module_inception)]
@@ -27,7 +29,10 @@ pub use self::{
   addr::{
     Addr, ConversionError as AddrConversionError, ParseError as AddrParseError,
   },
-  conn_expr::{ConnectionExpr, RouteExpr, TunnelExpr},
+  conn_expr::{
+    CommandExpr, CommandScheme, ConnectionExpr, RouteExpr, TunnelExpr,
+  },
+  port_files::{load_port_file, PortFileDiscovery, DEFAULT_PORT_FILE_NAMES},
   port_set::{CannotConvertToPortSetError, Port, PortSet, PortSetParseError},
-  resolution::ConnectionExprSource,
+  resolution::{ConnectionExprSource, PORT_ENV_VARS},
 };
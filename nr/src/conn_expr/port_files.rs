@@ -0,0 +1,346 @@
+// conn_expr/port_files.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Discovering the port files written by the various build tools.
+
+use std::{
+  fmt, fs, io,
+  path::{Path, PathBuf},
+};
+
+use crate::error::Error;
+
+use super::conn_expr::ConnectionExpr;
+
+/// The port files we look for by default in the order of priority
+pub const DEFAULT_PORT_FILE_NAMES: &[&str] = &[
+  // nREPL itself, Clojure CLI, Leiningen, shadow-cljs
+  ".nrepl-port",
+  // shadow-cljs
+  ".shadow-cljs/nrepl.port",
+  // Leiningen (older versions)
+  "target/repl-port",
+  // Babashka
+  ".bb-nrepl-port",
+];
+
+/// How to look for the port file
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortFileDiscovery {
+  /// The port file paths, relative to the directory being searched, in the
+  /// order of priority
+  pub names: Vec<PathBuf>,
+  /// Pick the port file with the highest priority even when the port files
+  /// point to different servers
+  pub prefer_first: bool,
+}
+
+impl Default for PortFileDiscovery {
+  fn default() -> Self {
+    Self {
+      names: DEFAULT_PORT_FILE_NAMES.iter().map(PathBuf::from).collect(),
+      prefer_first: false,
+    }
+  }
+}
+
+impl PortFileDiscovery {
+  /// Searches the directory and its ancestors for the port files.
+  ///
+  /// The search stops at the nearest directory that has any of the port
+  /// files in it.
+  pub fn discover(&self, start: &Path) -> io::Result<Discovery> {
+    let start = start.canonicalize()?;
+    for dir in start.ancestors() {
+      let found = self
+        .names
+        .iter()
+        .enumerate()
+        .map(|(priority, name)| (priority, dir.join(name)))
+        .filter(|(_, path)| path.is_file())
+        .map(|(priority, path)| {
+          let conn_expr = load_port_file(&path);
+          PortFile {
+            path,
+            priority,
+            conn_expr,
+          }
+        })
+        .collect::<Vec<_>>();
+      if !found.is_empty() {
+        return Ok(Discovery {
+          start,
+          found,
+          prefer_first: self.prefer_first,
+        });
+      }
+    }
+    Ok(Discovery {
+      start,
+      found: vec![],
+      prefer_first: self.prefer_first,
+    })
+  }
+
+  /// Returns the directories in which the port files may appear.
+  pub fn dirs_to_watch(&self, start: &Path) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Ok(start) = start.canonicalize() {
+      for dir in start.ancestors() {
+        for name in self.names.iter() {
+          if let Some(parent) = dir.join(name).parent() {
+            if !dirs.iter().any(|d| d == parent) {
+              dirs.push(parent.to_owned());
+            }
+          }
+        }
+      }
+    }
+    dirs
+  }
+}
+
+#[derive(Debug)]
+pub struct PortFile {
+  pub path: PathBuf,
+  /// The index of the port file name in the discovery policy
+  pub priority: usize,
+  pub conn_expr: Result<ConnectionExpr, Error>,
+}
+
+/// The outcome of the port file search
+#[derive(Debug)]
+pub struct Discovery {
+  /// The directory where the search started
+  pub start: PathBuf,
+  /// The port files found in the nearest directory in the order of priority
+  pub found: Vec<PortFile>,
+  prefer_first: bool,
+}
+
+impl Discovery {
+  /// Picks the port file with the highest priority.
+  ///
+  /// Fails if the port files point to different servers, unless told to
+  /// prefer the first one, or if the picked port file cannot be parsed.
+  pub fn select(&self) -> Result<Option<&PortFile>, Error> {
+    let Some(first) = self.found.first() else {
+      return Ok(None);
+    };
+    if !self.prefer_first && self.servers().len() > 1 {
+      return Err(Error::AmbiguousPortFiles(
+        self
+          .found
+          .iter()
+          .map(|f| f.path.to_string_lossy().into_owned())
+          .collect::<Vec<_>>()
+          .join(", "),
+      ));
+    }
+    Ok(Some(first))
+  }
+
+  /// Returns the distinct servers the port files point to.  The port files
+  /// that cannot be parsed are skipped.
+  fn servers(&self) -> Vec<&ConnectionExpr> {
+    let mut servers = Vec::new();
+    for e in self.found.iter().filter_map(|f| f.conn_expr.as_ref().ok()) {
+      if !servers.contains(&e) {
+        servers.push(e);
+      }
+    }
+    servers
+  }
+}
+
+impl fmt::Display for Discovery {
+  /// Explains which port file was picked and why.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.found.is_empty() {
+      return writeln!(
+        f,
+        "No port file in {} or its ancestors",
+        self.start.display()
+      );
+    }
+    let selected = self.select();
+    // The server of the highest priority port file that can be parsed
+    let first_server = self.servers().first().copied();
+    for (ix, port_file) in self.found.iter().enumerate() {
+      let contents = match port_file.conn_expr {
+        Ok(_) => fs::read_to_string(&port_file.path)
+          .map(|s| s.trim().to_owned())
+          .unwrap_or_default(),
+        Err(_) => "<unparseable>".to_owned(),
+      };
+      let server = port_file.conn_expr.as_ref().ok();
+      let note = match (&selected, ix) {
+        (Ok(_), 0) => "selected: nearest directory, highest priority",
+        (Err(_), 0) => "highest priority",
+        _ if server.is_none() => "skipped: cannot be parsed",
+        (Ok(_), _) if server == first_server => {
+          "skipped: lower priority, same server"
+        }
+        (Ok(_), _) => "skipped: lower priority",
+        (Err(_), _) if server == first_server => "same server",
+        (Err(_), _) => "conflict: points to a different server",
+      };
+      writeln!(
+        f,
+        "{}: {} (priority {}; {})",
+        port_file.path.display(),
+        contents,
+        port_file.priority + 1,
+        note
+      )?;
+    }
+    if let Err(ref err) = selected {
+      writeln!(f, "Error: {}", err)?;
+    }
+    Ok(())
+  }
+}
+
+pub fn load_port_file(path: impl AsRef<Path>) -> Result<ConnectionExpr, Error> {
+  let path = path.as_ref();
+  fs::read_to_string(path)
+    .map_err(|e| {
+      if e.kind() == io::ErrorKind::NotFound {
+        Error::NotFound(path.to_string_lossy().into())
+      } else {
+        Error::CannotReadFile(path.to_string_lossy().into())
+      }
+    })?
+    .trim()
+    .parse()
+    .ok()
+    // A port file referring to another port file is not supported
+    .filter(|e| !matches!(e, ConnectionExpr::PortFile(_)))
+    .ok_or_else(|| Error::CannotParsePortFile(path.to_string_lossy().into()))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::{env, process};
+
+  struct TempTree(PathBuf);
+
+  impl TempTree {
+    fn new(name: &str) -> Self {
+      let root = env::temp_dir().join(format!(
+        "nr-port-files-{}-{}",
+        name,
+        process::id()
+      ));
+      let _ = fs::remove_dir_all(&root);
+      fs::create_dir_all(root.join("app/src")).unwrap();
+      Self(root)
+    }
+
+    fn write(&self, path: &str, contents: &str) {
+      let path = self.0.join(path);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(path, contents).unwrap();
+    }
+  }
+
+  impl Drop for TempTree {
+    fn drop(&mut self) {
+      let _ = fs::remove_dir_all(&self.0);
+    }
+  }
+
+  fn selected_name(discovery: &Discovery) -> Option<String> {
+    discovery
+      .select()
+      .unwrap()
+      .map(|f| f.path.file_name().unwrap().to_string_lossy().into_owned())
+  }
+
+  #[test]
+  fn nearest_directory_wins() {
+    let tree = TempTree::new("nearest");
+    tree.write(".nrepl-port", "1111");
+    tree.write("app/.bb-nrepl-port", "2222");
+    let policy = PortFileDiscovery::default();
+    let discovery = policy.discover(&tree.0.join("app/src")).unwrap();
+    assert_eq!(selected_name(&discovery), Some(".bb-nrepl-port".to_owned()));
+  }
+
+  #[test]
+  fn same_server_in_many_port_files_is_fine() {
+    let tree = TempTree::new("same");
+    tree.write("app/.nrepl-port", "7888");
+    tree.write("app/.shadow-cljs/nrepl.port", "7888");
+    let policy = PortFileDiscovery::default();
+    let discovery = policy.discover(&tree.0.join("app")).unwrap();
+    assert_eq!(discovery.found.len(), 2);
+    assert_eq!(selected_name(&discovery), Some(".nrepl-port".to_owned()));
+  }
+
+  #[test]
+  fn different_servers_are_ambiguous() {
+    let tree = TempTree::new("ambiguous");
+    tree.write("app/target/repl-port", "1111");
+    tree.write("app/.bb-nrepl-port", "2222");
+    let mut policy = PortFileDiscovery::default();
+    let discovery = policy.discover(&tree.0.join("app")).unwrap();
+    assert!(matches!(
+      discovery.select(),
+      Err(Error::AmbiguousPortFiles(_))
+    ));
+    policy.prefer_first = true;
+    let discovery = policy.discover(&tree.0.join("app")).unwrap();
+    assert_eq!(selected_name(&discovery), Some("repl-port".to_owned()));
+  }
+
+  #[test]
+  fn explains_conflicts() {
+    let tree = TempTree::new("explain");
+    tree.write("app/.nrepl-port", "1111");
+    tree.write("app/.shadow-cljs/nrepl.port", "1111");
+    tree.write("app/target/repl-port", "2222");
+    let policy = PortFileDiscovery::default();
+    let discovery = policy.discover(&tree.0.join("app")).unwrap();
+    let notes = discovery
+      .to_string()
+      .lines()
+      .filter_map(|line| line.strip_suffix(')')?.rsplit_once("; "))
+      .map(|(_, note)| note.to_owned())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      notes,
+      [
+        "highest priority",
+        "same server",
+        "conflict: points to a different server"
+      ]
+    );
+  }
+
+  #[test]
+  fn custom_names() {
+    let tree = TempTree::new("custom");
+    tree.write("app/.nrepl-port", "1111");
+    tree.write("app/.my-port", "2222");
+    let policy = PortFileDiscovery {
+      names: vec![".my-port".into()],
+      prefer_first: false,
+    };
+    let discovery = policy.discover(&tree.0.join("app")).unwrap();
+    assert_eq!(selected_name(&discovery), Some(".my-port".to_owned()));
+  }
+}
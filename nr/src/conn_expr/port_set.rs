@@ -13,7 +13,7 @@
 // License for the specific language governing permissions and limitations under
 // the License.
 
-use std::str;
+use std::{fmt, str};
 
 use super::parser::{self, Parser};
 
@@ -47,6 +47,18 @@ impl PortSet {
   }
 }
 
+impl fmt::Display for PortSet {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for (i, port) in self.0.iter().enumerate() {
+      if i > 0 {
+        f.write_str(",")?;
+      }
+      write!(f, "{}", port)?;
+    }
+    Ok(())
+  }
+}
+
 impl<'a> TryFrom<parser::Pair<'a, parser::Rule>> for PortSet {
   type Error = CannotConvertToPortSetError;
 
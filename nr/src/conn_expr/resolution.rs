@@ -13,11 +13,19 @@
 // License for the specific language governing permissions and limitations under
 // the License.
 
-use std::{fs, io, path, thread, time};
+use std::{fmt::Write, path, time};
 
 use crate::error::Error;
 
-use super::conn_expr::ConnectionExpr;
+use super::{
+  conn_expr::ConnectionExpr,
+  port_files::{load_port_file, PortFileDiscovery},
+  watch::DirWatcher,
+};
+
+/// The environment variables holding the connection expression in the order of
+/// precedence
+pub const PORT_ENV_VARS: &[&str] = &["NR_PORT", "NREPL_PORT"];
 
 #[derive(Debug)]
 pub enum ConnectionExprSource {
@@ -25,8 +33,10 @@ pub enum ConnectionExprSource {
   Direct(ConnectionExpr),
   /// Read the connection expression from the port file.
   PortFile {
-    /// Use this instead of the nearest .nrepl-port file.
+    /// Use this instead of discovering the port file.
     path: Option<path::PathBuf>,
+    /// How to discover the port file when not given.
+    discovery: PortFileDiscovery,
     /// If not available, give it this amount of time to appear.
     wait_for: Option<time::Duration>,
   },
@@ -34,7 +44,14 @@ pub enum ConnectionExprSource {
 
 impl From<ConnectionExpr> for ConnectionExprSource {
   fn from(e: ConnectionExpr) -> Self {
-    ConnectionExprSource::Direct(e)
+    match e {
+      ConnectionExpr::PortFile(path) => ConnectionExprSource::PortFile {
+        path: Some(path),
+        discovery: Default::default(),
+        wait_for: None,
+      },
+      e => ConnectionExprSource::Direct(e),
+    }
   }
 }
 
@@ -45,75 +62,213 @@ impl From<&ConnectionExpr> for ConnectionExprSource {
 }
 
 impl ConnectionExprSource {
+  /// Picks the source of the connection expression.
+  ///
+  /// The first one of the following is used: the `--port` option, the
+  /// `--port-file` option, the environment variables in `PORT_ENV_VARS`, and
+  /// the port file discovered in the working directory or its ancestors.
+  /// The environment variables are looked up with `env_var`.
+  pub fn select(
+    port: Option<&ConnectionExpr>,
+    port_file: Option<&path::Path>,
+    discovery: PortFileDiscovery,
+    wait_for: Option<time::Duration>,
+    env_var: impl Fn(&str) -> Option<String>,
+  ) -> Result<Self, Error> {
+    let given = match (port, port_file) {
+      (Some(e), _) => Some(e.clone()),
+      (None, Some(f)) => Some(ConnectionExpr::PortFile(f.to_owned())),
+      (None, None) => from_env(env_var)?,
+    };
+    Ok(match given {
+      Some(ConnectionExpr::PortFile(path)) => ConnectionExprSource::PortFile {
+        path: Some(path),
+        discovery,
+        wait_for,
+      },
+      Some(e) => ConnectionExprSource::Direct(e),
+      None => ConnectionExprSource::PortFile {
+        path: None,
+        discovery,
+        wait_for,
+      },
+    })
+  }
+
   pub fn resolve_expr(&self) -> Result<ConnectionExpr, Error> {
-    const THROTTLING_DELAY: time::Duration = time::Duration::from_millis(50);
     match self {
       ConnectionExprSource::Direct(e) => Ok(e.clone()),
       ConnectionExprSource::PortFile {
         path,
+        discovery,
         wait_for: None,
-      } => try_load_from_port_file(path.as_ref()),
+      } => try_load_from_port_file(path.as_ref(), discovery),
       ConnectionExprSource::PortFile {
         path,
+        discovery,
         wait_for: Some(duration),
       } => {
-        let deadline = time::SystemTime::now() + *duration;
+        let deadline = time::Instant::now() + *duration;
+        // Set up the watches before the first check so that we do not miss
+        // the file if it appears in between
+        let watcher = DirWatcher::new(&dirs_to_watch(path.as_ref(), discovery));
         loop {
-          match try_load_from_port_file(path.as_ref()) {
+          match try_load_from_port_file(path.as_ref(), discovery) {
             Ok(r) => return Ok(r),
-            Err(e) => match e {
-              Error::NotSpecified | Error::NotFound(_) => {
-                if time::SystemTime::now() >= deadline {
-                  return Err(Error::PortFileTimeout);
-                }
-              }
-              _ => return Err(e),
-            },
+            // The server may not have finished writing the file yet
+            Err(
+              Error::NotSpecified
+              | Error::NotFound(_)
+              | Error::CannotParsePortFile(_),
+            ) if time::Instant::now() < deadline => (),
+            Err(Error::NotSpecified | Error::NotFound(_)) => {
+              return Err(Error::PortFileTimeout)
+            }
+            Err(e) => return Err(e),
           }
-          thread::sleep(THROTTLING_DELAY);
+          watcher.wait(deadline);
         }
       }
     }
   }
+
+  /// Explains where the connection expression comes from.
+  pub fn explain(&self) -> Result<String, Error> {
+    let mut s = String::new();
+    match self {
+      ConnectionExprSource::Direct(_) => writeln!(
+        s,
+        "No port file is used; the server is given explicitly with --port \
+        or {}",
+        PORT_ENV_VARS.join(" or ")
+      )
+      .unwrap(),
+      ConnectionExprSource::PortFile {
+        path: Some(path), ..
+      } => writeln!(s, "Given explicitly: {}", path.display()).unwrap(),
+      ConnectionExprSource::PortFile {
+        path: None,
+        discovery,
+        ..
+      } => write!(
+        s,
+        "{}",
+        discovery
+          .discover(path::Path::new("."))
+          .map_err(|_| Error::Unknown)?
+      )
+      .unwrap(),
+    }
+    Ok(s)
+  }
+}
+
+fn from_env(
+  env_var: impl Fn(&str) -> Option<String>,
+) -> Result<Option<ConnectionExpr>, Error> {
+  for var in PORT_ENV_VARS {
+    if let Some(value) = env_var(var).filter(|v| !v.is_empty()) {
+      crate::info!("using the connection expression {:?} from {}", value, var);
+      return value
+        .parse()
+        .map(Some)
+        .map_err(|_| Error::BadPortInEnv(var));
+    }
+  }
+  Ok(None)
 }
 
 fn try_load_from_port_file(
   given: Option<impl AsRef<path::Path>>,
+  discovery: &PortFileDiscovery,
 ) -> Result<ConnectionExpr, Error> {
   if let Some(f) = given {
-    load_from_port_file(f)
-  } else if let Some(ref f) = find_port_file().map_err(|_| Error::Unknown)? {
-    load_from_port_file(f)
-  } else {
-    Err(Error::NotSpecified)
+    crate::info!("reading the port file {}", f.as_ref().display());
+    return load_port_file(f);
+  }
+  let discovered = discovery
+    .discover(path::Path::new("."))
+    .map_err(|_| Error::Unknown)?;
+  for port_file in discovered.found.iter() {
+    crate::debug!("found the port file {}", port_file.path.display());
+  }
+  match discovered.select()? {
+    Some(port_file) => {
+      crate::info!("reading the port file {}", port_file.path.display());
+      load_port_file(&port_file.path)
+    }
+    None => Err(Error::NotSpecified),
   }
 }
 
-fn find_port_file() -> io::Result<Option<path::PathBuf>> {
-  let current_dir = path::PathBuf::from(".").canonicalize()?;
-  for dir in current_dir.ancestors() {
-    let mut path = path::PathBuf::from(dir);
-    path.push(".nrepl-port");
-    if path.is_file() {
-      return Ok(Some(path));
-    }
+/// Returns the directories in which the port file may appear.
+fn dirs_to_watch(
+  given: Option<&path::PathBuf>,
+  discovery: &PortFileDiscovery,
+) -> Vec<path::PathBuf> {
+  match given {
+    Some(f) => vec![match f.parent() {
+      Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+      _ => path::PathBuf::from("."),
+    }],
+    None => discovery.dirs_to_watch(path::Path::new(".")),
   }
-  Ok(None)
 }
 
-fn load_from_port_file(
-  path: impl AsRef<path::Path>,
-) -> Result<ConnectionExpr, Error> {
-  let path = path.as_ref();
-  fs::read_to_string(path)
-    .map_err(|e| {
-      if e.kind() == io::ErrorKind::NotFound {
-        Error::NotFound(path.to_string_lossy().into())
-      } else {
-        Error::CannotReadFile(path.to_string_lossy().into())
-      }
-    })?
-    .trim()
-    .parse()
-    .map_err(|_| Error::CannotParsePortFile(path.to_string_lossy().into()))
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn select(
+    port: Option<&str>,
+    port_file: Option<&str>,
+    env: &[(&str, &str)],
+  ) -> Result<ConnectionExprSource, Error> {
+    ConnectionExprSource::select(
+      port.map(|p| p.parse().unwrap()).as_ref(),
+      port_file.map(path::Path::new),
+      Default::default(),
+      None,
+      |name| {
+        env
+          .iter()
+          .find(|(var, _)| *var == name)
+          .map(|(_, value)| (*value).to_owned())
+      },
+    )
+  }
+
+  fn given_path(src: ConnectionExprSource) -> Option<path::PathBuf> {
+    match src {
+      ConnectionExprSource::PortFile { path, .. } => path,
+      ConnectionExprSource::Direct(e) => panic!("unexpected {:?}", e),
+    }
+  }
+
+  #[test]
+  fn source_precedence() {
+    assert_eq!(given_path(select(None, None, &[]).unwrap()), None);
+    let env = [("NREPL_PORT", "@nrepl.port")];
+    assert_eq!(
+      given_path(select(None, None, &env).unwrap()),
+      Some("nrepl.port".into())
+    );
+    let env = [("NREPL_PORT", "@nrepl.port"), ("NR_PORT", "@nr.port")];
+    assert_eq!(
+      given_path(select(None, None, &env).unwrap()),
+      Some("nr.port".into())
+    );
+    assert_eq!(
+      given_path(select(None, Some("given.port"), &env).unwrap()),
+      Some("given.port".into())
+    );
+    assert!(matches!(
+      select(Some("7888"), Some("given.port"), &env),
+      Ok(ConnectionExprSource::Direct(_))
+    ));
+    assert!(matches!(
+      select(None, None, &[("NR_PORT", "no such host:1")]),
+      Err(Error::BadPortInEnv("NR_PORT"))
+    ));
+  }
 }
@@ -0,0 +1,130 @@
+// conn_expr/watch.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Waiting for files to appear in directories.
+//!
+//! On Linux the directories are watched with inotify.  Elsewhere, or should
+//! setting up the watches fail, we fall back to polling.
+
+use std::{path::PathBuf, sync::mpsc, thread, time};
+
+/// How often we check for changes when there is no inotify
+const POLLING_INTERVAL: time::Duration = time::Duration::from_millis(50);
+
+/// How often we check for changes even with inotify.  This covers the events
+/// we cannot see, like the creation of a directory that did not exist when the
+/// watches were set up.
+const FALLBACK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct DirWatcher {
+  events: Option<mpsc::Receiver<()>>,
+}
+
+impl DirWatcher {
+  /// Starts watching the directories for files being created, moved in, or
+  /// written to.
+  pub fn new(dirs: &[PathBuf]) -> Self {
+    Self {
+      events: watch(dirs),
+    }
+  }
+
+  /// Blocks until something happens in the watched directories, it is time
+  /// to check anyway, or the deadline is reached.
+  pub fn wait(&self, deadline: time::Instant) {
+    let now = time::Instant::now();
+    let remaining = deadline.saturating_duration_since(now);
+    match self.events {
+      Some(ref events) => {
+        let _ = events.recv_timeout(remaining.min(FALLBACK_INTERVAL));
+        // Drain the events that arrived in the same burst
+        while events.try_recv().is_ok() {}
+      }
+      None => thread::sleep(remaining.min(POLLING_INTERVAL)),
+    }
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn watch(dirs: &[PathBuf]) -> Option<mpsc::Receiver<()>> {
+  use inotify::{Inotify, WatchMask};
+
+  let mut inotify = Inotify::init().ok()?;
+  let mask = WatchMask::CREATE
+    | WatchMask::MOVED_TO
+    | WatchMask::CLOSE_WRITE
+    | WatchMask::MODIFY;
+  let mut watching = false;
+  for dir in dirs {
+    watching |= inotify.watches().add(dir, mask).is_ok();
+  }
+  if !watching {
+    return None;
+  }
+  let (tx, rx) = mpsc::channel();
+  // The thread blocks on reading the events until the process exits or the
+  // watcher is dropped and the next event fails to be delivered.
+  thread::spawn(move || {
+    let mut buffer = [0_u8; 4096];
+    while inotify.read_events_blocking(&mut buffer).is_ok() {
+      if tx.send(()).is_err() {
+        break;
+      }
+    }
+  });
+  Some(rx)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watch(_dirs: &[PathBuf]) -> Option<mpsc::Receiver<()>> {
+  None
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::{env, fs, process};
+
+  #[test]
+  fn wakes_up_when_file_appears() {
+    let dir = env::temp_dir().join(format!("nr-watch-test-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let watcher = DirWatcher::new(&[dir.clone()]);
+    let file = dir.join(".nrepl-port");
+    let writer = thread::spawn({
+      let file = file.clone();
+      move || {
+        thread::sleep(time::Duration::from_millis(100));
+        fs::write(file, "7888").unwrap();
+      }
+    });
+    let started = time::Instant::now();
+    let deadline = started + time::Duration::from_secs(5);
+    while !file.exists() && time::Instant::now() < deadline {
+      watcher.wait(deadline);
+    }
+    writer.join().unwrap();
+    assert!(file.exists());
+    // Well under the fallback interval on Linux, so the event woke us up
+    let limit = if cfg!(target_os = "linux") {
+      time::Duration::from_millis(500)
+    } else {
+      2 * FALLBACK_INTERVAL
+    };
+    assert!(started.elapsed() < limit);
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
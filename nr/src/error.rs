@@ -17,6 +17,7 @@ use std::io;
 
 use crate::{
   clojure::lex,
+  host_options::SshOptionError,
   version::{Version, VersionRange},
 };
 
@@ -45,11 +46,17 @@ pub enum Error {
   #[error("cannot resolve the IP address for the domain {0}")]
   DomainNotFound(String),
   #[error(
-    "the nREPL server address not specified; try using the --port or \
-    --port-file option or ensure that there is a port file .nrepl-port \
-    in the current working directory or its ancestors"
+    "the nREPL server address not specified; tried, in order, the --port \
+    option, the --port-file option, the NR_PORT and NREPL_PORT environment \
+    variables, and a port file (like .nrepl-port) in the current working \
+    directory or its ancestors"
   )]
   NotSpecified,
+  #[error(
+    "the port files {0} point to different servers; pick one with the \
+    --port-file option or use --first-port-file"
+  )]
+  AmbiguousPortFiles(String),
   #[error("unknown error")]
   Unknown,
   #[error("stdin conflict")]
@@ -58,10 +65,32 @@ pub enum Error {
   BadStdIn,
   #[error("bad source file")]
   BadSourceFile,
+  #[error("bad script directive on line {1} of {0}")]
+  BadScriptDirective(String, usize),
   #[error("template arguments must be utf-8")]
   NonUtf8TemplateArgument,
   #[error("non-positional template argument must be named")]
   UnnamedNonPositionalTemplateArgument,
+  #[error("no value for template argument {2} on line {1} of {0}")]
+  MissingTemplateArgument(String, usize, String),
+  #[error("no values for template arguments {0}")]
+  MissingTemplateArguments(String),
+  #[error("cannot ask for template arguments on the terminal: {0}")]
+  CannotPrompt(io::Error),
+  #[error("bad template arguments file {0}: {1}")]
+  BadArgsFile(String, String),
+  #[error("template argument {0} is not used")]
+  UnusedTemplateArgument(String),
+  #[error(
+    "conflicting {2} directive for template argument {3} on line {1} of {0}"
+  )]
+  ConflictingTemplateDirective(String, usize, &'static str, String),
+  #[error("the value of template argument {2} on line {1} of {0} is not {3}")]
+  BadTemplateArgumentValue(String, usize, String, &'static str),
+  #[error("bad template placeholder on line {1} of {0}")]
+  BadTemplatePlaceholder(String, usize),
+  #[error("cannot parse line {1} of {0}")]
+  CannotParseSource(String, usize),
   #[error("timeout while waiting for port file")]
   PortFileTimeout,
   #[error("cannot find host definition for key \"{0}\"")]
@@ -71,10 +100,32 @@ pub enum Error {
     not supported yet"
   )]
   RecursiveHostKeysNotSupported(String),
+  #[error(
+    "host key \"{0}\" refers to a port file but this is not supported; give \
+    the port file with the --port-file option instead"
+  )]
+  PortFileHostNotSupported(String),
   #[error(
     "unexpected error while loading for default host configuration: {0}"
   )]
   FailedToLoadDefaultHostConfig(io::Error),
+  #[error("bad options for host \"{0}\": {1}")]
+  BadHostOptions(String, SshOptionError),
+  #[error(
+    "host \"{0}\" has a command but its connection is not of the form \
+    [host:]port"
+  )]
+  BadCommandHost(String),
+  #[error("bad connection expression in {0}")]
+  BadPortInEnv(&'static str),
+  #[error("cannot find the SSH client (ssh): {0}")]
+  SshClientNotFound(io::Error),
+  #[error(
+    "unsupported SSH client \"{0}\"; tunneling requires OpenSSH {1} or newer"
+  )]
+  UnsupportedSshClient(String, &'static str),
+  #[error("cannot close SSH control masters: {0}")]
+  CannotCloseSshControlMasters(io::Error),
 
   // Related to nREPL connection
   #[error("failed to connect to host: {0}")]
@@ -83,12 +134,18 @@ pub enum Error {
   CannotReceiveFromHost(io::Error),
   #[error("unexpected error while sending to host: {0}")]
   CannotSendToHost(io::Error),
+  #[error("cannot record the session: {0}")]
+  CannotRecordSession(io::Error),
   #[error("host sent corrupted response")]
   CorruptedResponse,
   #[error("host disconnected unexpectedly")]
   HostDisconnected,
   #[error("host sent unexptected response")]
   UnexptectedResponse,
+  #[error("host assertion failed: expected {0} but got {1}")]
+  HostAssertionFailed(String, String),
+  #[error("host assertion failed: checking {0} threw {1}")]
+  HostAssertionThrew(String, String),
 
   // Related to parsing Clojure
   #[error("failed to parse result: {0}")]
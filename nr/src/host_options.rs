@@ -13,9 +13,16 @@
 // License for the specific language governing permissions and limitations under
 // the License.
 
-use std::collections::HashMap;
+use std::{
+  collections::{BTreeMap, HashMap},
+  fmt,
+  path::PathBuf,
+  str,
+};
 
-use crate::conn_expr::ConnectionExpr;
+use crate::{
+  assertions::HostAssertion, conn_expr::ConnectionExpr, proxy::Proxy,
+};
 
 // FIXME: This is a bad name. Very easy to confuse with SSH host key.
 pub type HostKey = String;
@@ -27,4 +34,215 @@ pub struct HostOptions {
   pub name: Option<String>,
   pub conn_expr: ConnectionExpr,
   pub ask_confirmation: Option<bool>,
+  pub assertions: Vec<HostAssertion>,
+  pub ssh: SshOptions,
+  /// Command template for connecting through the command's stdio
+  pub command: Option<String>,
+  /// Proxy for direct connections; overrides `ALL_PROXY`
+  pub proxy: Option<Proxy>,
+}
+
+/// Options affecting how the SSH client is run when tunneling
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SshOptions {
+  /// Reuse connections through an OpenSSH control master
+  pub control_master: Option<bool>,
+  /// Seconds an idle control master lingers in the background
+  pub control_persist: Option<u64>,
+  /// Identity (private key) file used for authentication (`-i`)
+  pub identity_file: Option<PathBuf>,
+  /// Jump hosts to connect through (`-J`)
+  pub proxy_jump: Option<String>,
+  /// Host key checking policy
+  pub strict_host_key_checking: Option<StrictHostKeyChecking>,
+  /// Alternative per-user configuration file (`-F`)
+  pub config_file: Option<PathBuf>,
+  /// Additional options passed on with `-o`
+  pub extra_options: BTreeMap<String, String>,
+}
+
+impl SshOptions {
+  /// Fills in the options not set on `self` from `fallback`.
+  pub fn or(&self, fallback: &SshOptions) -> SshOptions {
+    SshOptions {
+      control_master: self.control_master.or(fallback.control_master),
+      control_persist: self.control_persist.or(fallback.control_persist),
+      identity_file: self
+        .identity_file
+        .clone()
+        .or_else(|| fallback.identity_file.clone()),
+      proxy_jump: self
+        .proxy_jump
+        .clone()
+        .or_else(|| fallback.proxy_jump.clone()),
+      strict_host_key_checking: self
+        .strict_host_key_checking
+        .or(fallback.strict_host_key_checking),
+      config_file: self
+        .config_file
+        .clone()
+        .or_else(|| fallback.config_file.clone()),
+      extra_options: fallback
+        .extra_options
+        .iter()
+        .chain(self.extra_options.iter())
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect(),
+    }
+  }
+
+  /// Checks that the extra options are among the ones we allow.
+  ///
+  /// The options that would execute local commands, set up forwardings, or
+  /// otherwise interfere with the way we run the client are not allowed.
+  pub fn validate(&self) -> Result<(), SshOptionError> {
+    for (key, value) in self.extra_options.iter() {
+      if !ALLOWED_EXTRA_OPTIONS
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(key))
+      {
+        return Err(SshOptionError::NotAllowed(key.clone()));
+      }
+      if value.is_empty() || value.contains(['\n', '\r']) {
+        return Err(SshOptionError::BadValue(key.clone()));
+      }
+    }
+    Ok(())
+  }
+}
+
+/// The extra SSH options that can be set through the hosts files
+const ALLOWED_EXTRA_OPTIONS: &[&str] = &[
+  "AddKeysToAgent",
+  "AddressFamily",
+  "BatchMode",
+  "BindAddress",
+  "CertificateFile",
+  "CheckHostIP",
+  "Ciphers",
+  "Compression",
+  "ConnectionAttempts",
+  "ConnectTimeout",
+  "GlobalKnownHostsFile",
+  "GSSAPIAuthentication",
+  "HostKeyAlgorithms",
+  "HostKeyAlias",
+  "HostName",
+  "IdentitiesOnly",
+  "IdentityAgent",
+  "IPQoS",
+  "KbdInteractiveAuthentication",
+  "KexAlgorithms",
+  "LogLevel",
+  "MACs",
+  "NumberOfPasswordPrompts",
+  "PasswordAuthentication",
+  "PreferredAuthentications",
+  "PubkeyAcceptedAlgorithms",
+  "PubkeyAuthentication",
+  "ServerAliveCountMax",
+  "ServerAliveInterval",
+  "TCPKeepAlive",
+  "UpdateHostKeys",
+  "UserKnownHostsFile",
+  "VerifyHostKeyDNS",
+];
+
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+pub enum SshOptionError {
+  #[error("SSH option {0} is not supported")]
+  NotAllowed(String),
+  #[error("bad value for SSH option {0}")]
+  BadValue(String),
+}
+
+/// The value of OpenSSH's `StrictHostKeyChecking` option
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StrictHostKeyChecking {
+  Yes,
+  No,
+  AcceptNew,
+  Ask,
+}
+
+impl fmt::Display for StrictHostKeyChecking {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    use StrictHostKeyChecking::*;
+    write!(
+      f,
+      "{}",
+      match self {
+        Yes => "yes",
+        No => "no",
+        AcceptNew => "accept-new",
+        Ask => "ask",
+      }
+    )
+  }
+}
+
+impl str::FromStr for StrictHostKeyChecking {
+  type Err = SshOptionError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    use StrictHostKeyChecking::*;
+    match s {
+      "yes" => Ok(Yes),
+      "no" | "off" => Ok(No),
+      "accept-new" => Ok(AcceptNew),
+      "ask" => Ok(Ask),
+      _ => Err(SshOptionError::BadValue("StrictHostKeyChecking".to_owned())),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn validate_extra_options() {
+    let with = |key: &str, value: &str| SshOptions {
+      extra_options: [(key.to_owned(), value.to_owned())].into(),
+      ..Default::default()
+    };
+    assert_eq!(with("ServerAliveInterval", "30").validate(), Ok(()));
+    assert_eq!(with("serveraliveinterval", "30").validate(), Ok(()));
+    assert_eq!(
+      with("ProxyCommand", "nc %h %p").validate(),
+      Err(SshOptionError::NotAllowed("ProxyCommand".to_owned()))
+    );
+    assert_eq!(
+      with("LocalForward", "1234 localhost:1234").validate(),
+      Err(SshOptionError::NotAllowed("LocalForward".to_owned()))
+    );
+    assert_eq!(
+      with("LogLevel", "").validate(),
+      Err(SshOptionError::BadValue("LogLevel".to_owned()))
+    );
+  }
+
+  #[test]
+  fn merging_options() {
+    let cli = SshOptions {
+      control_master: Some(false),
+      extra_options: [("LogLevel".to_owned(), "ERROR".to_owned())].into(),
+      ..Default::default()
+    };
+    let host = SshOptions {
+      control_master: Some(true),
+      proxy_jump: Some("bastion".to_owned()),
+      extra_options: [
+        ("LogLevel".to_owned(), "DEBUG".to_owned()),
+        ("BatchMode".to_owned(), "yes".to_owned()),
+      ]
+      .into(),
+      ..Default::default()
+    };
+    let merged = cli.or(&host);
+    assert_eq!(merged.control_master, Some(false));
+    assert_eq!(merged.proxy_jump.as_deref(), Some("bastion"));
+    assert_eq!(merged.extra_options["LogLevel"], "ERROR");
+    assert_eq!(merged.extra_options["BatchMode"], "yes");
+  }
 }
@@ -14,7 +14,7 @@
 // the License.
 
 use std::{
-  collections::HashMap,
+  collections::{BTreeMap, HashMap},
   env, fs,
   io::{self, Read},
   path::{Path, PathBuf},
@@ -24,9 +24,13 @@ use serde::Deserialize;
 use serde_with::{serde_as, DisplayFromStr};
 
 use crate::{
+  assertions::HostAssertion,
   conn_expr::ConnectionExpr,
   error::Error,
-  host_options::{HostKey, HostOptions, HostOptionsTable},
+  host_options::{
+    HostKey, HostOptions, HostOptionsTable, SshOptions, StrictHostKeyChecking,
+  },
+  proxy::Proxy,
 };
 
 pub fn load_default_hosts_files() -> Result<HostOptionsTable, Error> {
@@ -34,6 +38,7 @@ pub fn load_default_hosts_files() -> Result<HostOptionsTable, Error> {
   let ps = matching_config_files("nreplops-hosts.toml")
     .map_err(Error::FailedToLoadDefaultHostConfig)?;
   for p in ps.into_iter().rev() {
+    crate::info!("loading the hosts file {}", p.display());
     let mut f =
       fs::File::open(p).map_err(Error::FailedToLoadDefaultHostConfig)?;
     let mut s = String::new();
@@ -41,7 +46,14 @@ pub fn load_default_hosts_files() -> Result<HostOptionsTable, Error> {
       .read_to_string(&mut s)
       .map_err(Error::FailedToLoadDefaultHostConfig)?;
     let new_hosts: Hosts = toml::from_str(&s).unwrap();
-    hosts.extend(new_hosts.into_iter().map(|(k, v)| (k, v.into())));
+    for (key, opts) in new_hosts.into_iter() {
+      let opts: HostOptions = opts.into();
+      opts
+        .ssh
+        .validate()
+        .map_err(|e| Error::BadHostOptions(key.clone(), e))?;
+      hosts.insert(key, opts);
+    }
   }
   Ok(hosts)
 }
@@ -55,6 +67,24 @@ pub struct HostOptionsDe {
   #[serde_as(as = "DisplayFromStr")]
   connection: ConnectionExpr,
   confirm: Option<bool>,
+  assert_hostname: Option<String>,
+  #[serde(default)]
+  assert_env: BTreeMap<String, String>,
+  assert_form: Option<String>,
+  control_master: Option<bool>,
+  control_persist: Option<u64>,
+  identity_file: Option<PathBuf>,
+  proxy_jump: Option<String>,
+  #[serde_as(as = "Option<DisplayFromStr>")]
+  #[serde(default)]
+  strict_host_key_checking: Option<StrictHostKeyChecking>,
+  ssh_config: Option<PathBuf>,
+  #[serde(default)]
+  ssh_options: BTreeMap<String, String>,
+  command: Option<String>,
+  #[serde_as(as = "Option<DisplayFromStr>")]
+  #[serde(default)]
+  proxy: Option<Proxy>,
 }
 
 // `HostOptions` is independent of `HostOptionsDe` and, hence, we prefer
@@ -62,10 +92,34 @@ pub struct HostOptionsDe {
 #[allow(clippy::from_over_into)]
 impl Into<HostOptions> for HostOptionsDe {
   fn into(self) -> HostOptions {
+    let assertions = self
+      .assert_hostname
+      .map(HostAssertion::Hostname)
+      .into_iter()
+      .chain(
+        self
+          .assert_env
+          .into_iter()
+          .map(|(name, value)| HostAssertion::EnvVar { name, value }),
+      )
+      .chain(self.assert_form.map(HostAssertion::Form))
+      .collect();
     HostOptions {
       name: self.name,
       conn_expr: self.connection,
       ask_confirmation: self.confirm,
+      assertions,
+      ssh: SshOptions {
+        control_master: self.control_master,
+        control_persist: self.control_persist,
+        identity_file: self.identity_file,
+        proxy_jump: self.proxy_jump,
+        strict_host_key_checking: self.strict_host_key_checking,
+        config_file: self.ssh_config,
+        extra_options: self.ssh_options,
+      },
+      command: self.command,
+      proxy: self.proxy,
     }
   }
 }
@@ -21,18 +21,27 @@
   unused
 )]
 
+pub mod args_file;
+pub mod assertions;
+pub mod bencode;
 pub mod cli;
 pub mod clojure;
 pub mod conn_expr;
 pub mod error;
 pub mod host_options;
 pub mod hosts_files;
+pub mod log;
 pub mod nrepl;
 pub mod outputs;
 pub mod pprint;
+pub mod prompt;
+pub mod proxy;
+pub mod route_cache;
 pub mod routes;
+pub mod script_help;
 pub mod socket;
 pub mod sources;
+pub mod ssh;
+pub mod template;
+pub mod transcript;
 pub mod version;
-
-mod bencode;
@@ -0,0 +1,367 @@
+// log.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Diagnostic logging.
+//!
+//! Nothing is logged unless enabled with `-v` (what the program does while
+//! connecting), `-vv` (also the details like every candidate route), or
+//! `--trace-wire` (the nREPL messages).  The log goes to stderr unless a log
+//! file is given.
+//!
+//! The values of the secret template arguments are redacted from the log.
+
+use std::{
+  fmt, fs,
+  io::{self, Write},
+  path::Path,
+  process::Command,
+  sync::{
+    atomic::{AtomicBool, AtomicU8, Ordering},
+    Mutex, OnceLock,
+  },
+  time::SystemTime,
+};
+
+use serde_bencode::value::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+  Info = 1,
+  Debug = 2,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+static TRACE_WIRE: AtomicBool = AtomicBool::new(false);
+static LOG_FILE: OnceLock<Mutex<fs::File>> = OnceLock::new();
+#[cfg(not(test))]
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// The tests run in threads of their own; keeping the secrets per thread keeps
+// the tests from seeing each other's secrets.
+#[cfg(test)]
+thread_local! {
+  static SECRETS: std::cell::RefCell<Vec<String>> =
+    std::cell::RefCell::new(Vec::new());
+}
+
+/// The secrets shorter than this are not redacted; replacing them would mangle
+/// the unrelated parts of the log.
+const MIN_SECRET_LEN: usize = 4;
+
+#[cfg(not(test))]
+fn with_secrets<T>(f: impl FnOnce(&mut Vec<String>) -> T) -> Option<T> {
+  SECRETS.lock().ok().map(|mut secrets| f(&mut secrets))
+}
+
+#[cfg(test)]
+fn with_secrets<T>(f: impl FnOnce(&mut Vec<String>) -> T) -> Option<T> {
+  Some(SECRETS.with(|secrets| f(&mut secrets.borrow_mut())))
+}
+
+/// Sets up the logging; the log file, if given, is appended to.
+pub fn init(
+  verbosity: u8,
+  trace_wire: bool,
+  log_file: Option<&Path>,
+) -> io::Result<()> {
+  if let Some(path) = log_file {
+    let file = fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(path)?;
+    let _ = LOG_FILE.set(Mutex::new(file));
+  }
+  VERBOSITY.store(verbosity, Ordering::Relaxed);
+  TRACE_WIRE.store(trace_wire, Ordering::Relaxed);
+  Ok(())
+}
+
+pub fn enabled(level: Level) -> bool {
+  VERBOSITY.load(Ordering::Relaxed) >= level as u8
+}
+
+/// Logs an nREPL message sent to or received from the server.
+pub fn wire(sent: bool, payload: &[u8]) {
+  if TRACE_WIRE.load(Ordering::Relaxed) {
+    let arrow = if sent { "-->" } else { "<--" };
+    write(format_args!("{} {}", arrow, Bencode(payload)));
+  }
+}
+
+/// Keeps the secret out of the log from now on.
+pub fn add_secret(secret: &str) {
+  if secret.chars().count() < MIN_SECRET_LEN {
+    crate::info!(
+      "not redacting a secret shorter than {} characters",
+      MIN_SECRET_LEN
+    );
+    return;
+  }
+  with_secrets(|secrets| {
+    if !secrets.iter().any(|s| s == secret) {
+      secrets.push(secret.to_owned());
+      // The longest first so that a secret containing another one goes whole
+      secrets.sort_by_key(|s| usize::MAX - s.len());
+    }
+  });
+}
+
+/// Replaces the secrets in the text.
+pub fn redact(text: &str) -> String {
+  let mut text = text.to_owned();
+  with_secrets(|secrets| {
+    for secret in secrets.iter() {
+      text = text.replace(secret.as_str(), "***");
+    }
+  });
+  text
+}
+
+/// Replaces the secrets in the strings of the bencoded message.  The message
+/// is encoded anew so that the lengths of the strings stay right.
+pub fn redact_bencode(payload: &[u8]) -> Vec<u8> {
+  if with_secrets(|secrets| !secrets.is_empty()).unwrap_or(false) {
+    if let Ok(value) = serde_bencode::from_bytes::<Value>(payload) {
+      if let Ok(redacted) = serde_bencode::to_bytes(&redact_value(value)) {
+        return redacted;
+      }
+    }
+  }
+  payload.to_vec()
+}
+
+fn redact_value(value: Value) -> Value {
+  match value {
+    Value::Bytes(bytes) => match String::from_utf8(bytes) {
+      Ok(s) => Value::Bytes(redact(&s).into_bytes()),
+      Err(e) => Value::Bytes(e.into_bytes()),
+    },
+    Value::List(items) => {
+      Value::List(items.into_iter().map(redact_value).collect())
+    }
+    Value::Dict(entries) => Value::Dict(
+      entries
+        .into_iter()
+        .map(|(key, value)| (key, redact_value(value)))
+        .collect(),
+    ),
+    value => value,
+  }
+}
+
+#[doc(hidden)]
+pub fn write(args: fmt::Arguments) {
+  let line = format!(
+    "{} nr: {}\n",
+    Timestamp(SystemTime::now()),
+    redact(&args.to_string())
+  );
+  match LOG_FILE.get() {
+    Some(file) => {
+      if let Ok(mut file) = file.lock() {
+        let _ = file.write_all(line.as_bytes());
+      }
+    }
+    None => {
+      let _ = io::stderr().write_all(line.as_bytes());
+    }
+  }
+}
+
+/// Logs what the program is doing; enabled with `-v`.
+#[macro_export]
+macro_rules! info {
+  ($($arg:tt)*) => {
+    if $crate::log::enabled($crate::log::Level::Info) {
+      $crate::log::write(format_args!($($arg)*))
+    }
+  };
+}
+
+/// Logs the details; enabled with `-vv`.
+#[macro_export]
+macro_rules! debug {
+  ($($arg:tt)*) => {
+    if $crate::log::enabled($crate::log::Level::Debug) {
+      $crate::log::write(format_args!($($arg)*))
+    }
+  };
+}
+
+/// Formats the command as it could be typed in the shell.
+#[derive(Debug)]
+pub struct CommandLine<'a>(pub &'a Command);
+
+impl<'a> fmt::Display for CommandLine<'a> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let words = [self.0.get_program()].into_iter().chain(self.0.get_args());
+    for (i, word) in words.enumerate() {
+      if i > 0 {
+        f.write_str(" ")?;
+      }
+      let word = word.to_string_lossy();
+      if !word.is_empty()
+        && word
+          .chars()
+          .all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,[]".contains(c))
+      {
+        f.write_str(&word)?;
+      } else {
+        write!(f, "'{}'", word.replace('\'', r"'\''"))?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Formats a bencoded message in a readable, EDN-like form.
+pub(crate) struct Bencode<'a>(pub(crate) &'a [u8]);
+
+impl<'a> fmt::Display for Bencode<'a> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match serde_bencode::from_bytes::<Value>(self.0) {
+      Ok(value) => fmt_value(&value, f),
+      Err(_) => write!(f, "<{} bytes of bad bencode>", self.0.len()),
+    }
+  }
+}
+
+fn fmt_value(value: &Value, f: &mut fmt::Formatter) -> fmt::Result {
+  match value {
+    Value::Int(i) => write!(f, "{}", i),
+    // Redacted before escaping; the escaped secret would not be recognized
+    Value::Bytes(bytes) => match std::str::from_utf8(bytes) {
+      Ok(s) => write!(f, "{:?}", redact(s)),
+      Err(_) => write!(f, "<{} bytes>", bytes.len()),
+    },
+    Value::List(items) => {
+      f.write_str("[")?;
+      for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+          f.write_str(" ")?;
+        }
+        fmt_value(item, f)?;
+      }
+      f.write_str("]")
+    }
+    Value::Dict(entries) => {
+      let mut entries = entries.iter().collect::<Vec<_>>();
+      entries.sort_by(|a, b| a.0.cmp(b.0));
+      f.write_str("{")?;
+      for (i, (key, value)) in entries.into_iter().enumerate() {
+        if i > 0 {
+          f.write_str(", ")?;
+        }
+        write!(f, "{:?} ", String::from_utf8_lossy(key))?;
+        fmt_value(value, f)?;
+      }
+      f.write_str("}")
+    }
+  }
+}
+
+/// Formats the time as in `2024-05-01T12:34:56.789Z`.
+struct Timestamp(SystemTime);
+
+impl fmt::Display for Timestamp {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let since_epoch = self
+      .0
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    write!(
+      f,
+      "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+      year,
+      month,
+      day,
+      secs / 3600 % 24,
+      secs / 60 % 60,
+      secs % 60,
+      since_epoch.subsec_millis()
+    )
+  }
+}
+
+/// Converts days since the Unix epoch to a (proleptic Gregorian) date.  See
+/// Howard Hinnant's "chrono-Compatible Low-Level Date Algorithms".
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z.rem_euclid(146097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+  let year = yoe + era * 400 + i64::from(month <= 2);
+  (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn timestamps() {
+    let at = |secs, millis| {
+      Timestamp(
+        SystemTime::UNIX_EPOCH
+          + Duration::from_secs(secs)
+          + Duration::from_millis(millis),
+      )
+      .to_string()
+    };
+    assert_eq!(at(0, 0), "1970-01-01T00:00:00.000Z");
+    assert_eq!(at(951_827_696, 7), "2000-02-29T12:34:56.007Z");
+    assert_eq!(at(1_735_689_599, 999), "2024-12-31T23:59:59.999Z");
+  }
+
+  #[test]
+  fn bencode_messages() {
+    let msg = b"d2:id3:s:14:codel7:(+ 1 2)i1ee2:op4:evale";
+    assert_eq!(
+      Bencode(msg).to_string(),
+      r#"{"code" ["(+ 1 2)" 1], "id" "s:1", "op" "eval"}"#
+    );
+    assert_eq!(Bencode(b"d2:").to_string(), "<3 bytes of bad bencode>");
+  }
+
+  #[test]
+  fn redacts_secrets_from_messages() {
+    add_secret(r#"pa"ss"#);
+    add_secret(r#""pa\"ss""#);
+    let msg = br#"d4:code16:(login "pa\"ss")2:op4:evale"#;
+    assert_eq!(
+      Bencode(msg).to_string(),
+      r#"{"code" "(login ***)", "op" "eval"}"#
+    );
+    assert_eq!(redact_bencode(msg), b"d4:code11:(login ***)2:op4:evale");
+    assert_eq!(redact_bencode(b"d2:"), b"d2:");
+  }
+
+  #[test]
+  fn command_lines() {
+    let mut cmd = Command::new("ssh");
+    cmd.args(["-o", "ControlPath=/tmp/%C", "-W", "[::1]:7888", "it's here"]);
+    assert_eq!(
+      CommandLine(&cmd).to_string(),
+      r"ssh -o ControlPath=/tmp/%C -W [::1]:7888 'it'\''s here'"
+    );
+  }
+}
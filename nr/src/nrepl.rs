@@ -18,7 +18,12 @@ use std::io::ErrorKind;
 use serde::{Deserialize, Serialize};
 
 use super::socket::Socket;
-use crate::{bencode, error::Error};
+use crate::{
+  bencode,
+  error::Error,
+  log,
+  transcript::{Direction, Recorder},
+};
 
 #[derive(Debug)]
 pub struct Session {
@@ -170,6 +175,7 @@ impl WireResponse {
 pub struct Connection {
   socket: Socket,
   buffer: Vec<u8>,
+  recorder: Option<Recorder>,
 }
 
 impl Connection {
@@ -177,9 +183,15 @@ impl Connection {
     Self {
       socket,
       buffer: Default::default(),
+      recorder: None,
     }
   }
 
+  /// Records the frames sent and received from now on.
+  pub fn record(&mut self, recorder: Recorder) {
+    self.recorder = Some(recorder);
+  }
+
   pub fn session(mut self) -> Result<Session, Error> {
     self.send(WireRequest {
       op: Op::Clone,
@@ -207,6 +219,12 @@ impl Connection {
 
   fn send(&mut self, request: WireRequest) -> Result<(), Error> {
     let payload = serde_bencode::to_bytes(&request).unwrap();
+    log::wire(true, &payload);
+    if let Some(ref mut recorder) = self.recorder {
+      recorder
+        .record(Direction::Sent, &payload)
+        .map_err(Error::CannotRecordSession)?;
+    }
     let w = self.socket.borrow_mut_write();
     w.write_all(&payload).map_err(Error::CannotSendToHost)?;
     w.flush().map_err(Error::CannotSendToHost)
@@ -220,6 +238,12 @@ impl Connection {
     loop {
       match bencode::scan_next(&self.buffer) {
         Ok((_, len)) => {
+          log::wire(false, &self.buffer[0..len]);
+          if let Some(ref mut recorder) = self.recorder {
+            recorder
+              .record(Direction::Received, &self.buffer[0..len])
+              .map_err(Error::CannotRecordSession)?;
+          }
           let result = serde_bencode::from_bytes(&self.buffer[0..len])
             .map_err(|_| Error::CorruptedResponse);
           self.buffer.copy_within(len.., 0);
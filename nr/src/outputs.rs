@@ -27,7 +27,8 @@ use crate::{
   cli::{self, IoArg},
   clojure::lex,
   error::Error,
-  pprint::ClojureResultPrinter,
+  log,
+  pprint::{self, ClojureResultPrinter},
 };
 
 #[derive(Clone, Debug)]
@@ -143,6 +144,8 @@ pub struct Outputs {
   pub nrepl_stderr: Option<Output>,
   // Receives result forms
   pub nrepl_results: Option<NreplResultsSink>,
+  // Receives the code sent to nREPL
+  pub exprs: Option<ExprsSink>,
 }
 
 impl Outputs {
@@ -152,9 +155,17 @@ impl Outputs {
     }
 
     fn file_dst_from_path(path: &path::Path) -> Result<Dst, Error> {
-      path
+      // The file need not exist yet but the directory must
+      let name = path
+        .file_name()
+        .ok_or_else(|| err_cannot_write_file(path))?;
+      let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => path::Path::new("."),
+      };
+      dir
         .canonicalize()
-        .map(|p| Dst::File(p.into()))
+        .map(|dir| Dst::File(dir.join(name).into()))
         .map_err(|_| err_cannot_write_file(path))
     }
 
@@ -182,6 +193,11 @@ impl Outputs {
       Some(IoArg::File(ref p)) => connect(Src::Results, file_dst_from_path(p)?),
       None => (),
     }
+    match args.exprs_to {
+      Some(IoArg::Pipe) => connect(Src::Exprs, Dst::StdOut),
+      Some(IoArg::File(ref p)) => connect(Src::Exprs, file_dst_from_path(p)?),
+      None => (),
+    }
 
     fn determine_std_type<S>(s: S) -> StdType
     where
@@ -205,6 +221,7 @@ impl Outputs {
     let mut nrepl_stdout = None;
     let mut nrepl_stderr = None;
     let mut nrepl_results = None;
+    let mut exprs = None;
 
     for (sink, sources) in logical_connections.into_iter() {
       let output = match sink {
@@ -237,6 +254,12 @@ impl Outputs {
               },
             })
           }
+          Src::Exprs => {
+            exprs = Some(ExprsSink {
+              output: output.clone(),
+              color: args.color.to_bool(output.is_terminal()),
+            })
+          }
         }
       }
     }
@@ -247,6 +270,7 @@ impl Outputs {
       nrepl_stdout,
       nrepl_stderr,
       nrepl_results,
+      exprs,
     })
   }
 }
@@ -257,6 +281,7 @@ enum Src {
   StdOut,
   StdErr,
   Results,
+  Exprs,
 }
 
 // Local destinations for output
@@ -288,3 +313,23 @@ impl NreplResultsSink {
     .map_err(|e| self.output.generate_error(e))
   }
 }
+
+#[derive(Debug)]
+pub struct ExprsSink {
+  output: Output,
+  color: bool,
+}
+
+impl ExprsSink {
+  /// Echoes the code with the secrets redacted.
+  pub fn output(&self, code: &str) -> Result<(), Error> {
+    let code = log::redact(code);
+    let mut writer = self.output.writer();
+    match lex::lex(&code) {
+      Ok(lexemes) => pprint::print_code(&mut writer, &lexemes, self.color)
+        .and_then(|_| writeln!(writer)),
+      Err(_) => writeln!(writer, "{}", code),
+    }
+    .map_err(|e| self.output.generate_error(e))
+  }
+}
@@ -106,3 +106,26 @@ impl ClojureResultPrinter {
     writeln!(writer)
   }
 }
+
+/// Prints the code as it is, only colored if asked to.
+pub fn print_code(
+  writer: &mut impl Write,
+  lexemes: &[Lexeme],
+  color: bool,
+) -> io::Result<()> {
+  let mut printer_input = Vec::new();
+  unformatted_edn::generate_printer_input(lexemes.iter(), &mut printer_input);
+  printer::print(writer, printer_input.iter(), color)
+}
+
+/// Prints the value as plain Clojure, for example, for putting it into the
+/// source.
+pub fn to_clojure(value: &result_ir::Value) -> String {
+  let mut printer_input = Vec::new();
+  let chunks = pretty_edn::convert_to_layout_program(value);
+  layout_solver::solve(&chunks, &mut printer_input);
+  let mut buffer = Vec::new();
+  printer::print(&mut buffer, printer_input.iter(), false)
+    .expect("writing to memory does not fail");
+  String::from_utf8(buffer).expect("the printer writes UTF-8")
+}
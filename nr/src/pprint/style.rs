@@ -38,6 +38,8 @@ pub enum Style {
   TaggedLiteralDecoration,
   TaggedLiteralNamespace,
   TaggedLiteralName,
+  ReaderMacroDecoration,
+  Comment,
 }
 
 impl Style {
@@ -66,6 +68,8 @@ impl Style {
       S::TaggedLiteralDecoration => A::BrightBlack,
       S::TaggedLiteralNamespace => A::BrightBlack,
       S::TaggedLiteralName => A::Yellow,
+      S::ReaderMacroDecoration => A::BrightBlack,
+      S::Comment => A::BrightBlack,
     }
   }
 }
@@ -13,8 +13,10 @@
 // License for the specific language governing permissions and limitations under
 // the License.
 
-//! Converts the result IR into a layout problem that should produce in a
-//! relatively pretty EDN output once solved.
+//! Converts the lexemes into printer input as they are, only styled.
+//!
+//! Besides the results this covers the code sent to the server, so all the
+//! lexemes have a style.
 
 use crate::clojure::lex::Lexeme;
 
@@ -120,7 +122,34 @@ pub fn generate_printer_input<'a, I>(
       | L::EndMap { source, .. } => {
         printer_input.add_styled(S::CollectionDelimiter, source)
       }
-      ref unhandled => todo!("no rule for: {:?}", unhandled),
+      L::Comment { source } => printer_input.add_styled(S::Comment, source),
+      L::Meta { source, .. }
+      | L::Discard { source, .. }
+      | L::Quote { source, .. }
+      | L::Synquote { source, .. }
+      | L::Unquote { source, .. }
+      | L::SplicingUnquote { source, .. }
+      | L::ReaderConditionalPrefix { source, .. } => {
+        printer_input.add_styled(S::ReaderMacroDecoration, source)
+      }
+      L::Char { source, .. } => {
+        printer_input.add_styled(S::StringValue, source)
+      }
+      L::Regex { source, .. } => {
+        printer_input.add_styled(S::StringDecoration, "#\"");
+        printer_input.add_styled(S::StringValue, &source[2..source.len() - 1]);
+        printer_input.add_styled(S::StringDecoration, "\"");
+      }
+      L::MapQualifier { source, .. } => {
+        printer_input.add_styled(S::KeywordNamespace, source)
+      }
+      L::StartAnonymousFn { source, .. }
+      | L::EndAnonymousFn { source, .. }
+      | L::StartReaderConditional { source, .. }
+      | L::EndReaderConditional { source, .. } => {
+        printer_input.add_styled(S::CollectionDelimiter, source)
+      }
+      L::Residual(ref pair) => printer_input.add_plain(pair.as_str()),
     }
   }
 }
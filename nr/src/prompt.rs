@@ -0,0 +1,100 @@
+// prompt.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Asking the user for the missing template arguments on the terminal.
+//!
+//! The terminal is used directly through `/dev/tty` so that the prompts work
+//! even when the program is read from stdin.
+
+use std::{
+  fs,
+  io::{self, BufRead, IsTerminal, Write},
+  process::{Command, Stdio},
+};
+
+use crate::template::ArgSpec;
+
+#[derive(Debug)]
+pub struct Prompt {
+  reader: io::BufReader<fs::File>,
+  writer: fs::File,
+}
+
+impl Prompt {
+  /// Opens the terminal if the program is run interactively.
+  pub fn open() -> Option<Self> {
+    if !io::stderr().is_terminal() {
+      return None;
+    }
+    let tty = fs::OpenOptions::new()
+      .read(true)
+      .write(true)
+      .open("/dev/tty")
+      .ok()?;
+    Some(Self {
+      writer: tty.try_clone().ok()?,
+      reader: io::BufReader::new(tty),
+    })
+  }
+
+  /// Asks for the value of the argument until the user gives a valid one.
+  ///
+  /// Returns `None` if the user gives up, i.e. closes the input.
+  pub fn ask(&mut self, spec: &ArgSpec) -> io::Result<Option<String>> {
+    let mut label = spec.name.as_deref().unwrap_or(spec.key).to_owned();
+    if let Some(ref help) = spec.help {
+      label.push_str(&format!(" ({})", help));
+    }
+    if let Some(ref var) = spec.env {
+      label.push_str(&format!(" [env: {}]", var));
+    }
+    loop {
+      write!(self.writer, "{}: ", label)?;
+      self.writer.flush()?;
+      let mut line = String::new();
+      let read = if spec.secret {
+        // The secrets are not echoed; the line break neither, so add one
+        self.set_echo(false)?;
+        let read = self.reader.read_line(&mut line);
+        self.set_echo(true)?;
+        writeln!(self.writer)?;
+        read?
+      } else {
+        self.reader.read_line(&mut line)?
+      };
+      if read == 0 {
+        if !spec.secret {
+          writeln!(self.writer)?;
+        }
+        return Ok(None);
+      }
+      let value = line.trim_end_matches(&['\n', '\r'][..]);
+      match spec.check(value) {
+        Ok(()) => return Ok(Some(value.to_owned())),
+        Err(expected) => {
+          writeln!(self.writer, "The value must be {}.", expected)?
+        }
+      }
+    }
+  }
+
+  fn set_echo(&self, on: bool) -> io::Result<()> {
+    Command::new("stty")
+      .arg(if on { "echo" } else { "-echo" })
+      .stdin(Stdio::from(self.writer.try_clone()?))
+      .status()
+      .map(|_| ())
+  }
+}
@@ -0,0 +1,171 @@
+// route_cache.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Remembering the tunneled routes that worked.
+//!
+//! Finding the working route through a tunnel with several candidate ports may
+//! take many SSH connections.  The winning route is stored in a small cache
+//! file, keyed by the connection expression and the SSH options, and tried
+//! first the next time.  Expressions with a single route are not cached.
+
+use std::{
+  collections::BTreeMap,
+  env, fs, io,
+  path::{Path, PathBuf},
+  process,
+  time::{self, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How long a cached route is trusted unless told otherwise
+pub const DEFAULT_ROUTE_CACHE_TTL: time::Duration =
+  time::Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct RouteCache {
+  #[serde(default)]
+  routes: BTreeMap<String, CachedRoute>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CachedRoute {
+  route: String,
+  /// Seconds since the Unix epoch
+  saved_at: u64,
+}
+
+impl RouteCache {
+  /// Loads the cache from the default location.  A missing or broken cache
+  /// file yields an empty cache.
+  pub fn load() -> Self {
+    cache_file()
+      .and_then(|path| Self::load_from(&path).ok())
+      .unwrap_or_default()
+  }
+
+  pub fn load_from(path: &Path) -> io::Result<Self> {
+    match fs::read_to_string(path) {
+      Ok(s) => toml::from_str(&s)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+      Err(e) => Err(e),
+    }
+  }
+
+  /// Saves the cache to the default location dropping the expired entries.
+  pub fn save(&mut self, ttl: time::Duration) -> io::Result<()> {
+    let path = cache_file().ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::NotFound,
+        "neither XDG_CACHE_HOME nor HOME is set",
+      )
+    })?;
+    self.save_to(&path, ttl)
+  }
+
+  pub fn save_to(
+    &mut self,
+    path: &Path,
+    ttl: time::Duration,
+  ) -> io::Result<()> {
+    let now = now();
+    self
+      .routes
+      .retain(|_, cached| now.saturating_sub(cached.saved_at) < ttl.as_secs());
+    if let Some(dir) = path.parent() {
+      fs::create_dir_all(dir)?;
+    }
+    let contents = toml::to_string(self)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    // Write and rename so that concurrent runs never see a partial file
+    let tmp = path.with_extension(format!("{}.tmp", process::id()));
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+  }
+
+  /// Returns the cached route unless it has expired.
+  pub fn get(&self, key: &str, ttl: time::Duration) -> Option<&str> {
+    self
+      .routes
+      .get(key)
+      .filter(|cached| now().saturating_sub(cached.saved_at) < ttl.as_secs())
+      .map(|cached| cached.route.as_str())
+  }
+
+  pub fn insert(&mut self, key: String, route: String) {
+    self.routes.insert(
+      key,
+      CachedRoute {
+        route,
+        saved_at: now(),
+      },
+    );
+  }
+}
+
+/// Returns the path of the cache file.
+///
+/// This is `${XDG_CACHE_HOME}/nreplops/routes.toml`, if the cache directory is
+/// defined, or `${HOME}/.cache/nreplops/routes.toml` otherwise.
+fn cache_file() -> Option<PathBuf> {
+  let dir = env::var_os("XDG_CACHE_HOME")
+    .map(PathBuf::from)
+    .filter(|p| p.is_absolute())
+    .or_else(|| {
+      env::var_os("HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .map(|p| p.join(".cache"))
+    })?;
+  Some(dir.join("nreplops").join("routes.toml"))
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn round_trip_and_expiry() {
+    let dir = env::temp_dir().join(format!("nr-route-cache-{}", process::id()));
+    let path = dir.join("routes.toml");
+    let ttl = time::Duration::from_secs(60);
+
+    let mut cache = RouteCache::load_from(&path).unwrap();
+    assert_eq!(cache.get("bastion:app:1,2,3", ttl), None);
+    cache.insert("bastion:app:1,2,3".to_owned(), "bastion:app:2".to_owned());
+    cache.routes.insert(
+      "old:app:1,2".to_owned(),
+      CachedRoute {
+        route: "old:app:1".to_owned(),
+        saved_at: now() - 120,
+      },
+    );
+    assert_eq!(cache.get("old:app:1,2", ttl), None);
+    cache.save_to(&path, ttl).unwrap();
+
+    let cache = RouteCache::load_from(&path).unwrap();
+    assert_eq!(cache.get("bastion:app:1,2,3", ttl), Some("bastion:app:2"));
+    assert!(!cache.routes.contains_key("old:app:1,2"));
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -13,32 +13,84 @@
 // License for the specific language governing permissions and limitations under
 // the License.
 
-use std::net;
+use std::{
+  fmt, net,
+  process::{Command, Stdio},
+};
 
 use crate::{
-  conn_expr::{Addr, ConnectionExpr, Port, PortSet, RouteExpr},
+  conn_expr::{
+    load_port_file, Addr, CommandExpr, ConnectionExpr, Port, PortSet,
+    RouteExpr, TunnelExpr,
+  },
   error::Error,
-  host_options::HostOptionsTable,
+  host_options::{HostOptionsTable, SshOptions},
+  log,
+  proxy::{self, Proxy},
+  ssh,
 };
 
+/// Resolves the routes to the host.
+///
+/// The given SSH options take precedence over the ones set for the host in the
+/// hosts files.
 pub fn resolve_routes(
   conn_expr: &ConnectionExpr,
   host_opts_table: &HostOptionsTable,
+  ssh_opts: &SshOptions,
 ) -> Result<Routes, Error> {
   use ConnectionExpr::*;
-  let route_expr = match conn_expr {
-    RouteExpr(ref e) => e,
-    HostKey(ref k) => host_opts_table
-      .get(k)
-      .ok_or_else(|| Error::HostKeyNotFound(k.to_string()))?
-      .conn_expr
-      .try_as_route_expr()
-      .ok_or_else(|| Error::RecursiveHostKeysNotSupported(k.to_string()))?,
+  let inner = match conn_expr {
+    RouteExpr(ref e) => {
+      RoutesInner::try_from_route_expr(e, ssh_opts.clone(), None)?
+    }
+    CommandExpr(ref e) => RoutesInner::from_command_expr(e),
+    PortFile(ref path) => {
+      return resolve_routes(&load_port_file(path)?, host_opts_table, ssh_opts)
+    }
+    HostKey(ref k) => {
+      let host_opts = host_opts_table
+        .get(k)
+        .ok_or_else(|| Error::HostKeyNotFound(k.to_string()))?;
+      match (&host_opts.conn_expr, &host_opts.command) {
+        (RouteExpr(ref e), Some(template)) if !e.is_tunneled() => {
+          RoutesInner::Command {
+            template: template.clone(),
+            host: e
+              .addr
+              .as_ref()
+              .map_or_else(|| "localhost".to_owned(), |a| a.to_string()),
+            ports: e.ports.clone(),
+          }
+        }
+        (_, Some(_)) => return Err(Error::BadCommandHost(k.to_string())),
+        (RouteExpr(ref e), None) => RoutesInner::try_from_route_expr(
+          e,
+          ssh_opts.or(&host_opts.ssh),
+          host_opts.proxy.clone(),
+        )?,
+        (CommandExpr(ref e), None) => RoutesInner::from_command_expr(e),
+        (PortFile(_), None) => {
+          return Err(Error::PortFileHostNotSupported(k.to_string()))
+        }
+        (HostKey(_), None) => {
+          return Err(Error::RecursiveHostKeysNotSupported(k.to_string()))
+        }
+      }
+    }
   };
-  Ok(Routes {
-    inner: RoutesInner::try_from_route_expr(route_expr)?,
+  let routes = Routes {
+    inner,
     pos: 0,
-  })
+    first: None,
+  };
+  crate::info!("{} route(s) to the host", routes.inner.len());
+  if log::enabled(log::Level::Debug) {
+    for route in routes.clone() {
+      crate::debug!("  {}", route);
+    }
+  }
+  Ok(routes)
 }
 
 #[derive(Clone, Debug)]
@@ -49,6 +101,45 @@ pub enum Route {
   // the name resolution behaves the same as it would when you debug it by
   // hand with the actual ssh client.
   Tunneled(TunnelOptions),
+  /// Connect through the stdio of a local command
+  Command(CommandOptions),
+  /// Connect through a SOCKS5 or HTTP proxy; the name resolution is left to
+  /// the proxy unless it is a `socks5://` one
+  Proxied(ProxiedOptions),
+}
+
+impl fmt::Display for Route {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Route::Direct(addr) => write!(f, "{}", addr),
+      Route::Tunneled(opts) => write!(f, "ssh tunnel {}", opts),
+      Route::Command(opts) => write!(f, "command `{}`", opts.command_line()),
+      Route::Proxied(opts) => write!(
+        f,
+        "{}:{} through proxy {}",
+        opts.host_addr.to_expr_string(),
+        opts.host_port,
+        opts.proxy
+      ),
+    }
+  }
+}
+
+impl Route {
+  /// Returns the route as stored in the route cache, if it is cacheable.
+  pub fn cache_value(&self) -> Option<String> {
+    match self {
+      Route::Tunneled(opts) => Some(opts.to_string()),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct ProxiedOptions {
+  pub proxy: Proxy,
+  pub host_addr: Addr,
+  pub host_port: Port,
 }
 
 #[derive(Clone, Debug)]
@@ -56,14 +147,144 @@ pub struct TunnelOptions {
   pub ssh_user: Option<String>,
   pub ssh_addr: Addr,
   pub ssh_port: Option<Port>,
+  /// The SSH hosts to jump through before reaching the SSH host
+  pub jump_hosts: Vec<JumpHost>,
   pub host_addr: Addr,
   pub host_port: Port,
+  pub ssh_options: SshOptions,
+}
+
+impl fmt::Display for TunnelOptions {
+  /// Formats the route as a connection expression without the SSH options.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let ssh_host = JumpHost {
+      user: self.ssh_user.clone(),
+      addr: self.ssh_addr.clone(),
+      port: self.ssh_port,
+    };
+    for hop in self.jump_hosts.iter().chain([&ssh_host]) {
+      write!(f, "{}:", hop)?;
+    }
+    write!(f, "{}:{}", self.host_addr.to_expr_string(), self.host_port)
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct CommandOptions {
+  /// The command line with `{host}` and `{port}` placeholders
+  pub template: String,
+  pub host: String,
+  pub port: Port,
+}
+
+impl CommandOptions {
+  /// Builds the command with the placeholders filled in.
+  ///
+  /// The command line is run by the shell, like OpenSSH does with its
+  /// `ProxyCommand`.  The substituted values come from the connection
+  /// expression whose grammar does not allow any shell metacharacters in them.
+  pub fn command(&self) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd
+      .arg("-c")
+      .arg(format!("exec {}", self.command_line()))
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped());
+    cmd
+  }
+
+  /// Returns the command line with the placeholders filled in.
+  pub fn command_line(&self) -> String {
+    self
+      .template
+      .replace("{host}", &self.host)
+      .replace("{port}", &self.port.to_string())
+  }
+
+  /// Returns the name of the program for diagnostics.
+  pub fn program(&self) -> &str {
+    self.template.split_whitespace().next().unwrap_or("command")
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct JumpHost {
+  pub user: Option<String>,
+  pub addr: Addr,
+  pub port: Option<Port>,
+}
+
+impl fmt::Display for JumpHost {
+  /// Formats the host in the `[user@]host[:port]` form.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if let Some(ref user) = self.user {
+      write!(f, "{}@", user)?;
+    }
+    f.write_str(&self.addr.to_expr_string())?;
+    if let Some(port) = self.port {
+      write!(f, ":{}", port)?;
+    }
+    Ok(())
+  }
 }
 
 #[derive(Clone, Debug)]
 pub struct Routes {
   inner: RoutesInner,
   pos: usize,
+  /// The route to try before the others
+  first: Option<usize>,
+}
+
+impl Routes {
+  /// Returns `true` if the routes are tunneled through SSH.
+  pub fn is_tunneled(&self) -> bool {
+    matches!(self.inner, RoutesInner::Tunneled { .. })
+  }
+
+  /// Returns the key under which the winning route is cached.  Only the
+  /// tunneled routes with more than one candidate are worth caching.
+  ///
+  /// The key is the connection expression followed by the ssh arguments the
+  /// SSH options turn into, if any, as the options may decide which of the
+  /// routes work.
+  pub fn cache_key(&self) -> Option<String> {
+    match self.inner {
+      RoutesInner::Tunneled {
+        ref hops,
+        ref host_addr,
+        ref host_ports,
+        ref ssh_options,
+      } if self.inner.len() > 1 => {
+        let expr = RouteExpr {
+          ports: host_ports.clone(),
+          addr: Some(host_addr.clone()),
+          tunnel: hops.clone(),
+        };
+        let mut key = expr.to_string();
+        if let Some(ref jump) = ssh_options.proxy_jump {
+          key.push_str(" -J ");
+          key.push_str(jump);
+        }
+        for arg in ssh::host_option_args(ssh_options) {
+          key.push(' ');
+          key.push_str(&arg.to_string_lossy());
+        }
+        Some(key)
+      }
+      _ => None,
+    }
+  }
+
+  /// Moves the route, given as formatted by `Route::cache_value`, to the
+  /// front.  Returns `false` if there is no such route.
+  pub fn prefer(&mut self, route: &str) -> bool {
+    self.first = (0..self.inner.len()).find(|&ix| {
+      self.inner.produce(ix).cache_value().as_deref() == Some(route)
+    });
+    self.first.is_some()
+  }
 }
 
 impl Iterator for Routes {
@@ -71,9 +292,13 @@ impl Iterator for Routes {
 
   fn next(&mut self) -> Option<Self::Item> {
     if self.pos < self.inner.len() {
-      let item = self.inner.produce(self.pos);
+      let ix = match self.first {
+        Some(first) if self.pos == 0 => first,
+        Some(first) if self.pos <= first => self.pos - 1,
+        _ => self.pos,
+      };
       self.pos += 1;
-      Some(item)
+      Some(self.inner.produce(ix))
     } else {
       None
     }
@@ -87,28 +312,62 @@ enum RoutesInner {
     ports: PortSet,
   },
   Tunneled {
-    ssh_user: Option<String>,
-    ssh_addr: Addr,
-    ssh_ports: Option<PortSet>,
+    hops: Vec<TunnelExpr>,
+    host_addr: Addr,
+    host_ports: PortSet,
+    ssh_options: SshOptions,
+  },
+  Command {
+    template: String,
+    host: String,
+    ports: PortSet,
+  },
+  Proxied {
+    proxy: Proxy,
     host_addr: Addr,
     host_ports: PortSet,
   },
 }
 
 impl RoutesInner {
-  fn try_from_route_expr(route_expr: &RouteExpr) -> Result<Self, Error> {
-    if let Some(ref tunnel) = route_expr.tunnel {
+  fn from_command_expr(command_expr: &CommandExpr) -> Self {
+    RoutesInner::Command {
+      template: command_expr.scheme.template().to_owned(),
+      host: command_expr.target.clone(),
+      ports: command_expr.ports.clone(),
+    }
+  }
+
+  /// Builds the routes for the expression.  The proxy, if given, overrides the
+  /// one set in the environment but it is not used for tunneled connections.
+  fn try_from_route_expr(
+    route_expr: &RouteExpr,
+    ssh_options: SshOptions,
+    proxy: Option<Proxy>,
+  ) -> Result<Self, Error> {
+    if route_expr.is_tunneled() {
       let host_addr = route_expr
         .addr
         .as_ref()
         .expect("tunneling should guarantee final host address")
         .clone();
       Ok(RoutesInner::Tunneled {
-        ssh_user: tunnel.user.clone(),
-        ssh_addr: tunnel.addr.clone(),
-        ssh_ports: tunnel.ports.clone(),
+        hops: route_expr.tunnel.clone(),
         host_addr,
         host_ports: route_expr.ports.clone(),
+        ssh_options,
+      })
+    } else if let Some(proxy) = match proxy {
+      Some(proxy) => Some(proxy),
+      None => proxy::from_env(route_expr.addr.as_ref()),
+    } {
+      Ok(RoutesInner::Proxied {
+        proxy,
+        host_addr: route_expr
+          .addr
+          .clone()
+          .unwrap_or_else(|| Addr::Domain("localhost".to_owned())),
+        host_ports: route_expr.ports.clone(),
       })
     } else {
       let mut ips = match route_expr.addr {
@@ -132,15 +391,13 @@ impl RoutesInner {
         addrs.len() * ports.as_slice().len()
       }
       RoutesInner::Tunneled {
-        ssh_ports: None,
-        host_ports,
-        ..
-      } => host_ports.as_slice().len(),
-      RoutesInner::Tunneled {
-        ssh_ports: Some(ssh_ports),
-        host_ports,
-        ..
-      } => ssh_ports.as_slice().len() * host_ports.as_slice().len(),
+        hops, host_ports, ..
+      } => {
+        hops.iter().map(hop_port_count).product::<usize>()
+          * host_ports.as_slice().len()
+      }
+      RoutesInner::Command { ports, .. } => ports.as_slice().len(),
+      RoutesInner::Proxied { host_ports, .. } => host_ports.as_slice().len(),
     }
   }
 
@@ -157,40 +414,276 @@ impl RoutesInner {
         ))
       }
       RoutesInner::Tunneled {
-        ssh_user,
-        ssh_addr,
-        ssh_ports: None,
-        host_addr,
-        host_ports,
-      } => Route::Tunneled(TunnelOptions {
-        ssh_user: ssh_user.clone(),
-        ssh_addr: ssh_addr.clone(),
-        ssh_port: None,
-        host_addr: host_addr.clone(),
-        host_port: host_ports.as_slice()[ix],
-      }),
-      RoutesInner::Tunneled {
-        ssh_user,
-        ssh_addr,
-
-        ssh_ports: Some(ssh_ports),
+        hops,
         host_addr,
         host_ports,
+        ssh_options,
       } => {
-        // Iterate ssh host's ports first and final host's ports second
-        let ssh_ports = ssh_ports.as_slice();
-        let ix_ssh_port = ix % ssh_ports.len();
-        let ix_host_port = ix / ssh_ports.len();
+        // Iterate the hops' ports in order, the first hop's ports changing
+        // fastest, and the final host's ports last
+        let mut ix = ix;
+        let mut jump_hosts = hops
+          .iter()
+          .map(|hop| {
+            let n = hop_port_count(hop);
+            let port = hop.ports.as_ref().map(|ps| ps.as_slice()[ix % n]);
+            ix /= n;
+            JumpHost {
+              user: hop.user.clone(),
+              addr: hop.addr.clone(),
+              port,
+            }
+          })
+          .collect::<Vec<_>>();
+        let ssh_host = jump_hosts
+          .pop()
+          .expect("tunneling should guarantee at least one hop");
         Route::Tunneled(TunnelOptions {
-          ssh_user: ssh_user.clone(),
-          ssh_addr: ssh_addr.clone(),
-          ssh_port: Some(ssh_ports[ix_ssh_port]),
+          ssh_user: ssh_host.user,
+          ssh_addr: ssh_host.addr,
+          ssh_port: ssh_host.port,
+          jump_hosts,
           host_addr: host_addr.clone(),
-          host_port: host_ports.as_slice()[ix_host_port],
+          host_port: host_ports.as_slice()[ix],
+          ssh_options: ssh_options.clone(),
         })
       }
+      RoutesInner::Command {
+        template,
+        host,
+        ports,
+      } => Route::Command(CommandOptions {
+        template: template.clone(),
+        host: host.clone(),
+        port: ports.as_slice()[ix],
+      }),
+      RoutesInner::Proxied {
+        proxy,
+        host_addr,
+        host_ports,
+      } => Route::Proxied(ProxiedOptions {
+        proxy: proxy.clone(),
+        host_addr: host_addr.clone(),
+        host_port: host_ports.as_slice()[ix],
+      }),
     }
   }
 }
 
-// FIXME: Tests
+fn hop_port_count(hop: &TunnelExpr) -> usize {
+  hop.ports.as_ref().map_or(1, |ps| ps.as_slice().len())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::host_options::HostOptions;
+
+  #[test]
+  fn preferred_route_goes_first() {
+    let expr = "alice@a:1,2:[::1]:5,6".parse::<ConnectionExpr>().unwrap();
+    let mut routes = resolve_routes(
+      &expr,
+      &HostOptionsTable::default(),
+      &SshOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(routes.cache_key().as_deref(), Some("alice@a:1,2:[::1]:5,6"));
+    let all = |routes: &Routes| {
+      routes
+        .clone()
+        .map(|r| r.cache_value().unwrap())
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(
+      all(&routes),
+      [
+        "alice@a:1:[::1]:5",
+        "alice@a:2:[::1]:5",
+        "alice@a:1:[::1]:6",
+        "alice@a:2:[::1]:6"
+      ]
+    );
+    assert!(routes.prefer("alice@a:1:[::1]:6"));
+    assert_eq!(
+      all(&routes),
+      [
+        "alice@a:1:[::1]:6",
+        "alice@a:1:[::1]:5",
+        "alice@a:2:[::1]:5",
+        "alice@a:2:[::1]:6"
+      ]
+    );
+    assert!(!routes.prefer("alice@a:3:[::1]:6"));
+    assert_eq!(all(&routes)[0], "alice@a:1:[::1]:5");
+  }
+
+  #[test]
+  fn cache_key() {
+    let key = |expr: &str, ssh_opts: &SshOptions| {
+      resolve_routes(
+        &expr.parse::<ConnectionExpr>().unwrap(),
+        &HostOptionsTable::default(),
+        ssh_opts,
+      )
+      .unwrap()
+      .cache_key()
+    };
+    let defaults = SshOptions::default();
+    assert_eq!(key("a:b:1-3", &defaults).as_deref(), Some("a:b:1,2,3"));
+    // A single route leaves nothing to remember
+    assert_eq!(key("a:b:1", &defaults), None);
+    assert_eq!(key("127.0.0.1:1-3", &defaults), None);
+    let with_identity = SshOptions {
+      identity_file: Some("id_other".into()),
+      ..Default::default()
+    };
+    assert_eq!(
+      key("a:b:1-3", &with_identity).as_deref(),
+      Some("a:b:1,2,3 -i id_other")
+    );
+    let with_jump_and_option = SshOptions {
+      proxy_jump: Some("j".to_owned()),
+      extra_options: [("Port".to_owned(), "2222".to_owned())].into(),
+      ..Default::default()
+    };
+    assert_eq!(
+      key("a:b:1-3", &with_jump_and_option).as_deref(),
+      Some("a:b:1,2,3 -J j -o Port=2222")
+    );
+  }
+
+  #[test]
+  fn multi_hop_routes() {
+    let expr = "a:1,2:b:c:3,4:d:5,6".parse::<ConnectionExpr>().unwrap();
+    let routes = resolve_routes(
+      &expr,
+      &HostOptionsTable::default(),
+      &SshOptions::default(),
+    )
+    .unwrap()
+    .map(|r| match r {
+      Route::Tunneled(opts) => (
+        opts.jump_hosts.iter().map(|j| j.port).collect::<Vec<_>>(),
+        opts.ssh_addr.to_string(),
+        opts.ssh_port,
+        opts.host_port,
+      ),
+      _ => panic!("expected a tunneled route"),
+    })
+    .collect::<Vec<_>>();
+    assert_eq!(routes.len(), 8);
+    assert_eq!(routes[0], (vec![Some(1), None], "c".to_owned(), Some(3), 5));
+    assert_eq!(routes[1], (vec![Some(2), None], "c".to_owned(), Some(3), 5));
+    assert_eq!(routes[2], (vec![Some(1), None], "c".to_owned(), Some(4), 5));
+    assert_eq!(routes[7], (vec![Some(2), None], "c".to_owned(), Some(4), 6));
+  }
+
+  fn command_lines(routes: Routes) -> Vec<String> {
+    routes
+      .map(|r| match r {
+        Route::Command(opts) => opts
+          .command()
+          .get_args()
+          .map(|a| a.to_str().unwrap())
+          .collect::<Vec<_>>()
+          .join(" "),
+        _ => panic!("expected a command route"),
+      })
+      .collect()
+  }
+
+  #[test]
+  fn host_proxy_routes() {
+    let mut table = HostOptionsTable::default();
+    table.insert(
+      "app".to_owned(),
+      HostOptions {
+        name: None,
+        conn_expr: "app.internal:7888,7889".parse().unwrap(),
+        ask_confirmation: None,
+        assertions: vec![],
+        ssh: SshOptions::default(),
+        command: None,
+        proxy: Some("socks5h://proxy:1080".parse().unwrap()),
+      },
+    );
+    let routes = resolve_routes(
+      &ConnectionExpr::HostKey("app".to_owned()),
+      &table,
+      &SshOptions::default(),
+    )
+    .unwrap()
+    .map(|r| match r {
+      Route::Proxied(opts) => {
+        format!("{} {}:{}", opts.proxy, opts.host_addr, opts.host_port)
+      }
+      _ => panic!("expected a proxied route"),
+    })
+    .collect::<Vec<_>>();
+    assert_eq!(
+      routes,
+      vec![
+        "socks5h://proxy:1080 app.internal:7888",
+        "socks5h://proxy:1080 app.internal:7889"
+      ]
+    );
+  }
+
+  #[test]
+  fn builtin_command_routes() {
+    let expr = "kubectl://my-app:7888,7889".parse().unwrap();
+    let routes = resolve_routes(
+      &expr,
+      &HostOptionsTable::default(),
+      &SshOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+      command_lines(routes),
+      vec![
+        "-c exec kubectl exec -i my-app -- nc localhost 7888",
+        "-c exec kubectl exec -i my-app -- nc localhost 7889"
+      ]
+    );
+  }
+
+  #[test]
+  fn host_command_routes() {
+    let mk_table = |connection: &str| {
+      let mut table = HostOptionsTable::default();
+      table.insert(
+        "app".to_owned(),
+        HostOptions {
+          name: None,
+          conn_expr: connection.parse().unwrap(),
+          ask_confirmation: None,
+          assertions: vec![],
+          ssh: SshOptions::default(),
+          command: Some("nc -q0 {host} {port}".to_owned()),
+          proxy: None,
+        },
+      );
+      table
+    };
+    let resolve = |table| {
+      resolve_routes(
+        &ConnectionExpr::HostKey("app".to_owned()),
+        &table,
+        &SshOptions::default(),
+      )
+    };
+    assert_eq!(
+      command_lines(resolve(mk_table("db:5555")).unwrap()),
+      vec!["-c exec nc -q0 db 5555"]
+    );
+    assert_eq!(
+      command_lines(resolve(mk_table("5555")).unwrap()),
+      vec!["-c exec nc -q0 localhost 5555"]
+    );
+    assert!(matches!(
+      resolve(mk_table("bastion:db:5555")),
+      Err(Error::BadCommandHost(_))
+    ));
+  }
+}
@@ -0,0 +1,209 @@
+// script_help.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! The `--help` of a script run in the shebang mode.
+//!
+//! The help is made of the comment block at the start of the script and the
+//! synopsis of the `#nr[...]` arguments the script takes.
+
+use std::fmt::Write;
+
+use crate::{
+  assertions::HostAssertion,
+  cli::SourceArg,
+  clojure::lex::{self, Lexeme as L},
+  error::Error,
+  sources,
+  template::{ArgSpec, Fallback, Template},
+  version::VersionRange,
+};
+
+/// Renders the help of the script.
+pub fn render(
+  source_arg: &SourceArg,
+  version_range: Option<&VersionRange>,
+) -> Result<String, Error> {
+  let (_, source) = sources::load_content(source_arg)?;
+  let name = match source_arg {
+    SourceArg::File(path) => path
+      .file_name()
+      .map(|name| name.to_string_lossy().into_owned()),
+    _ => None,
+  };
+  render_source(name.as_deref().unwrap_or("<input>"), &source, version_range)
+}
+
+fn render_source(
+  file: &str,
+  source: &str,
+  version_range: Option<&VersionRange>,
+) -> Result<String, Error> {
+  let template = Template::parse(file, source)?;
+
+  // The positional arguments go first and in order, then the rest argument
+  let mut args = template.args().iter().collect::<Vec<_>>();
+  args.sort_by_key(|spec| match position(spec) {
+    Some(pos) => pos,
+    None if spec.rest => usize::MAX - 1,
+    None => usize::MAX,
+  });
+
+  let mut help = String::new();
+  let description = description(source);
+  if !description.is_empty() {
+    for line in description {
+      let _ = writeln!(help, "{}", line);
+    }
+    help.push('\n');
+  }
+
+  let _ = write!(help, "Usage: {} [OPTIONS]", file);
+  for spec in args.iter() {
+    if is_optional(spec) {
+      let _ = write!(help, " [{}]", usage(spec));
+    } else {
+      let _ = write!(help, " {}", usage(spec));
+    }
+  }
+  help.push('\n');
+
+  if !args.is_empty() {
+    help.push_str("\nArguments:\n");
+    let width = args.iter().map(|spec| usage(spec).len()).max().unwrap_or(0);
+    for spec in args.iter() {
+      let mut line = format!("  {:width$}", usage(spec), width = width);
+      if let Some(ref text) = spec.help {
+        let _ = write!(line, "  {}", text);
+      }
+      if let Some(Fallback::Value(value)) = spec.fallback {
+        let _ = write!(line, "  [default: {}]", value);
+      }
+      if let Some(ref var) = spec.env {
+        let _ = write!(line, "  [env: {}]", var);
+      }
+      let _ = writeln!(help, "{}", line.trim_end());
+    }
+  }
+
+  if let Some(range) =
+    version_range.filter(|r| r.start.is_some() || r.end.is_some())
+  {
+    let _ = writeln!(help, "\nRequires nr version {}", range);
+  }
+
+  Ok(help)
+}
+
+/// Returns the lines of the comment block at the start of the source.
+///
+/// The shebang line and the script directives are left out.
+fn description(source: &str) -> Vec<&str> {
+  let mut lines = Vec::new();
+  let Ok(lexemes) = lex::lex(source) else {
+    return lines;
+  };
+  for lexeme in lexemes.iter() {
+    match lexeme {
+      L::Comment { source } if source.starts_with("#!") => (),
+      L::Comment { source } => {
+        let text = source.trim_start_matches(';');
+        if HostAssertion::parse_directive(text).is_none() {
+          lines.push(text.strip_prefix(' ').unwrap_or(text).trim_end());
+        }
+      }
+      L::Whitespace { source } => {
+        if source.matches('\n').count() > 1 && !lines.is_empty() {
+          lines.push("");
+        }
+      }
+      _ => break,
+    }
+  }
+  while lines.last() == Some(&"") {
+    lines.pop();
+  }
+  lines
+}
+
+fn position(spec: &ArgSpec) -> Option<usize> {
+  spec.key.parse().ok()
+}
+
+fn is_optional(spec: &ArgSpec) -> bool {
+  spec.rest || spec.fallback.is_some() || spec.env.is_some()
+}
+
+/// Returns how the argument is given on the command line.
+fn usage(spec: &ArgSpec) -> String {
+  match (position(spec), spec.name.as_deref()) {
+    (Some(_), Some(name)) => format!("<{}>", name),
+    (Some(pos), None) => format!("<arg{}>", pos),
+    (None, name) if spec.rest => format!("<{}>...", name.unwrap_or(spec.key)),
+    (None, name) => {
+      format!("-a {}=<{}>", spec.key, name.unwrap_or(spec.key))
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn renders_help() {
+    let source = r#"#!/usr/bin/env -S nr -! 0.3
+;; Greets someone.
+;;
+;; nr:assert-hostname app-1
+;; Politely.
+
+;; Really.
+(greet #nr[1 :name who :help "Who to greet"]
+       #nr[2 :or "Hello"]
+       #nr[loud :env LOUD :help "Shout"]
+       #nr[& more :help "And others"])
+;; Not part of the help
+"#;
+    let range = "0.3..0.4".parse::<VersionRange>().unwrap();
+    assert_eq!(
+      render_source("./greet.clj", source, Some(&range)).unwrap(),
+      r#"Greets someone.
+
+Politely.
+
+Really.
+
+Usage: ./greet.clj [OPTIONS] <who> [<arg2>] [<more>...] [-a loud=<loud>]
+
+Arguments:
+  <who>           Who to greet
+  <arg2>          [default: "Hello"]
+  <more>...       And others
+  -a loud=<loud>  Shout  [env: LOUD]
+
+Requires nr version 0.3.0..0.4.0
+"#
+    );
+  }
+
+  #[test]
+  fn renders_help_without_arguments() {
+    let any = "..".parse::<VersionRange>().unwrap();
+    assert_eq!(
+      render_source("x.clj", "(f)", Some(&any)).unwrap(),
+      "Usage: x.clj [OPTIONS]\n"
+    );
+  }
+}
@@ -14,20 +14,57 @@
 // the License.
 
 use super::{
+  bencode,
   error::Error,
+  log::{self, CommandLine},
+  proxy,
+  route_cache::RouteCache,
   routes::{Route, Routes},
+  ssh,
+  transcript::{self, Replay},
 };
 
 use std::{
-  io::{self, Read, Write},
+  io::{self, BufRead, BufReader, Read, Write},
   net::{self, TcpStream},
-  process::{Child, Command, Stdio},
+  path,
+  process::{Child, ChildStdout, Command},
+  sync::{mpsc, Arc, Mutex},
+  thread, time,
 };
 
+/// How long a single connection attempt may take unless told otherwise
+pub const DEFAULT_CONNECT_TIMEOUT: time::Duration =
+  time::Duration::from_secs(15);
+
+/// How long we wait for a direct connection attempt before starting the next
+/// one in parallel (the "Connection Attempt Delay" of RFC 8305)
+const STAGGER_DELAY: time::Duration = time::Duration::from_millis(250);
+
+/// The first and the longest delay between retries
+const INITIAL_RETRY_DELAY: time::Duration = time::Duration::from_millis(250);
+const MAX_RETRY_DELAY: time::Duration = time::Duration::from_secs(4);
+
+/// How long we wait for the diagnostics after the tunnel has failed
+const DIAGNOSTICS_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+/// A `describe` request used for checking that an nREPL server answers on
+/// the other end of the tunnel
+const HANDSHAKE_REQUEST: &[u8] = b"d2:id12:nr-handshake2:op8:describee";
+
 #[derive(Debug)]
 pub enum Socket {
   TcpStream(TcpStream),
-  SshClient(Child),
+  Child(ChildStream),
+  /// A recorded session played back
+  Replay(Replay),
+}
+
+/// A child process whose stdin and stdout act as the connection
+#[derive(Debug)]
+pub struct ChildStream {
+  child: Child,
+  stdout: BufReader<ChildStdout>,
 }
 
 impl From<TcpStream> for Socket {
@@ -40,20 +77,29 @@ impl Socket {
   pub fn borrow_mut_read(&mut self) -> &mut dyn Read {
     match *self {
       Socket::TcpStream(ref mut s) => s,
-      Socket::SshClient(ref mut p) => {
-        p.stdout.as_mut().expect("child process's stdout is piped")
-      }
+      Socket::Child(ref mut c) => &mut c.stdout,
+      Socket::Replay(ref mut r) => r,
     }
   }
 
   pub fn borrow_mut_write(&mut self) -> &mut dyn Write {
     match *self {
       Socket::TcpStream(ref mut s) => s,
-      Socket::SshClient(ref mut p) => {
-        p.stdin.as_mut().expect("child process's stdin is piped")
-      }
+      Socket::Child(ref mut c) => c
+        .child
+        .stdin
+        .as_mut()
+        .expect("child process's stdin is piped"),
+      Socket::Replay(ref mut r) => r,
     }
   }
+
+  /// Opens a socket that replays the transcript.
+  pub fn replay(path: &path::Path) -> Result<Self, Error> {
+    let frames = transcript::read(path)
+      .map_err(|_| Error::CannotReadFile(path.to_string_lossy().into()))?;
+    Ok(Socket::Replay(Replay::new(frames)))
+  }
 }
 
 impl Drop for Socket {
@@ -62,7 +108,9 @@ impl Drop for Socket {
       Socket::TcpStream(ref mut s) => {
         let _ignore = s.shutdown(net::Shutdown::Both);
       }
-      Socket::SshClient(ref mut p) => {
+      Socket::Child(ChildStream {
+        child: ref mut p, ..
+      }) => {
         if let Ok(Some(_)) = p.try_wait() {
           // The child process has already stopped
         } else {
@@ -70,76 +118,614 @@ impl Drop for Socket {
           let _ = p.kill();
         }
       }
+      Socket::Replay(_) => (),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+  /// How long a single connection attempt may take
+  pub timeout: time::Duration,
+  /// Keep retrying failed connections with a backoff until this instant
+  pub retry_until: Option<time::Instant>,
+  /// Check that an nREPL server answers also on direct and proxied
+  /// connections before accepting them
+  pub handshake: bool,
+  /// Try the tunneled route that worked the last time first, if it is no
+  /// older than this
+  pub route_cache_ttl: Option<time::Duration>,
+}
+
+impl Default for ConnectOptions {
+  fn default() -> Self {
+    Self {
+      timeout: DEFAULT_CONNECT_TIMEOUT,
+      retry_until: None,
+      handshake: false,
+      route_cache_ttl: None,
+    }
+  }
+}
+
+pub fn connect(
+  mut routes: Routes,
+  opts: &ConnectOptions,
+) -> Result<Socket, Error> {
+  let cached = opts
+    .route_cache_ttl
+    .zip(routes.cache_key())
+    .map(|(ttl, key)| (RouteCache::load(), key, ttl));
+  if let Some((ref cache, ref key, ttl)) = cached {
+    if let Some(route) = cache.get(key, ttl) {
+      if routes.prefer(route) {
+        crate::info!("trying the cached route {} first", route);
+      }
+    }
+  }
+  let deadline = opts.retry_until;
+  let mut delay = INITIAL_RETRY_DELAY;
+  loop {
+    match connect_any(routes.clone(), opts) {
+      Ok((socket, route)) => {
+        crate::info!("connected through {}", route);
+        if let (Some((mut cache, key, ttl)), Some(value)) =
+          (cached, route.cache_value())
+        {
+          cache.insert(key, value);
+          // The cache is an optimization; failing to save it is no reason to
+          // fail the connection
+          let _ = cache.save(ttl);
+        }
+        return Ok(socket);
+      }
+      Err(err)
+        if is_transient(&err)
+          && deadline.map_or(false, |d| time::Instant::now() + delay < d) =>
+      {
+        crate::info!("failed to connect ({}); retrying in {:?}", err, delay);
+        thread::sleep(delay);
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+      }
+      Err(err) => return Err(Error::FailedToConnectToHost(err)),
     }
   }
 }
 
-pub fn connect(mut routes: Routes) -> Result<Socket, Error> {
-  let first_route = routes.next().expect("there is at least one route");
-  match connect_impl(&first_route) {
-    Ok(socket) => Ok(socket),
-    Err(first_err) if first_err.kind() == io::ErrorKind::ConnectionRefused => {
-      for route in routes {
-        match connect_impl(&route) {
-          Ok(socket) => {
-            return Ok(socket);
+/// Checks whether the error could go away when the server has had time to
+/// start.
+fn is_transient(err: &io::Error) -> bool {
+  matches!(
+    err.kind(),
+    io::ErrorKind::ConnectionRefused
+      | io::ErrorKind::ConnectionReset
+      | io::ErrorKind::ConnectionAborted
+      | io::ErrorKind::TimedOut
+      | io::ErrorKind::UnexpectedEof
+  )
+}
+
+/// Tries the routes in parallel and returns the first connection that
+/// succeeds.
+///
+/// The attempts are started in the order of the routes.  The next attempt is
+/// started when the previous one fails or, in case of direct and proxied
+/// connections, after a short delay in the manner of Happy Eyeballs (RFC 8305).
+/// Each attempt is given up after the timeout.  If all attempts fail the error
+/// of the first route is returned.
+fn connect_any(
+  routes: Routes,
+  opts: &ConnectOptions,
+) -> Result<(Socket, Route), io::Error> {
+  let (tx, rx) = mpsc::channel();
+  let mut routes = routes.enumerate().peekable();
+  let mut in_flight = Vec::<(usize, time::Instant)>::new();
+  let mut errors = Vec::<(usize, io::Error)>::new();
+  // The routes for logging as they are moved to the attempts
+  let mut labels = Vec::<String>::new();
+  let mut next_start = time::Instant::now();
+  loop {
+    let now = time::Instant::now();
+    if now >= next_start {
+      if let Some((ix, route)) = routes.next() {
+        let stagger = route.stagger_delay();
+        let tx = tx.clone();
+        let attempt_opts = opts.clone();
+        crate::info!("connecting through {}", route);
+        labels.push(route.to_string());
+        thread::spawn(move || {
+          // The receiver is gone if another attempt won already; dropping
+          // the socket closes the connection.
+          let result = connect_impl(&route, &attempt_opts);
+          if let Err(ref err) = result {
+            crate::info!("{} failed: {}", route, err);
+          }
+          let _ = tx.send((ix, result.map(|socket| (socket, route))));
+        });
+        in_flight.push((ix, now + opts.timeout));
+        next_start = stagger.map_or(far_future(now), |d| now + d);
+      }
+    }
+    if in_flight.is_empty() && routes.peek().is_none() {
+      errors.sort_by_key(|(ix, _)| *ix);
+      return Err(errors.into_iter().next().map(|(_, e)| e).unwrap_or_else(
+        || io::Error::new(io::ErrorKind::NotFound, "no routes to host"),
+      ));
+    }
+    let mut wait_until = in_flight
+      .iter()
+      .map(|(_, deadline)| *deadline)
+      .min()
+      .unwrap_or_else(|| far_future(now));
+    if routes.peek().is_some() {
+      wait_until = wait_until.min(next_start);
+    }
+    match rx.recv_timeout(wait_until.saturating_duration_since(now)) {
+      Ok((ix, Ok(connected))) => {
+        let now = time::Instant::now();
+        match in_flight.iter().position(|(i, _)| *i == ix) {
+          Some(pos) if in_flight[pos].1 > now => return Ok(connected),
+          // Too late; the timeout bounds the whole attempt.  Dropping the
+          // socket closes the connection.
+          Some(pos) => {
+            in_flight.remove(pos);
+            crate::info!("{} timed out", labels[ix]);
+            errors.push((ix, timed_out()));
+            next_start = now;
           }
-          Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
-            continue;
+          // Given up on already
+          None => (),
+        }
+      }
+      Ok((ix, Err(err))) => {
+        if let Some(pos) = in_flight.iter().position(|(i, _)| *i == ix) {
+          in_flight.remove(pos);
+          errors.push((ix, err));
+        }
+        // Move on to the next route right away
+        next_start = time::Instant::now();
+      }
+      Err(_) => {
+        let now = time::Instant::now();
+        in_flight.retain(|(ix, deadline)| {
+          if *deadline <= now {
+            crate::info!("{} timed out", labels[*ix]);
+            errors.push((*ix, timed_out()));
+            false
+          } else {
+            true
           }
-          Err(err) => return Err(Error::FailedToConnectToHost(err)),
+        });
+        if in_flight.is_empty() {
+          next_start = now;
         }
       }
-      Err(Error::FailedToConnectToHost(first_err))
     }
-    Err(err) => Err(Error::FailedToConnectToHost(err)),
   }
 }
 
-fn connect_impl(route: &Route) -> Result<Socket, io::Error> {
+fn timed_out() -> io::Error {
+  io::Error::new(io::ErrorKind::TimedOut, "connection timed out")
+}
+
+fn far_future(now: time::Instant) -> time::Instant {
+  now + time::Duration::from_secs(24 * 60 * 60)
+}
+
+impl Route {
+  /// Returns how long to wait before starting the next attempt in parallel or
+  /// `None` if the next attempt should wait for this one to fail.
+  fn stagger_delay(&self) -> Option<time::Duration> {
+    match self {
+      Route::Direct(_) | Route::Proxied(_) => Some(STAGGER_DELAY),
+      // Spawning a bunch of ssh or kubectl processes in parallel would be
+      // a bit rude
+      Route::Tunneled(_) | Route::Command(_) => None,
+    }
+  }
+}
+
+fn connect_impl(
+  route: &Route,
+  opts: &ConnectOptions,
+) -> Result<Socket, io::Error> {
   use Route::*;
-  match *route {
+  let timeout = opts.timeout;
+  let socket = match *route {
     Direct(ip) => {
-      let s = TcpStream::connect(ip)?;
+      let s = TcpStream::connect_timeout(&ip, timeout)?;
       s.set_nodelay(true)?;
-      Ok(Socket::from(s))
-    }
-    Tunneled(ref opts) => {
-      let mut cmd = Command::new("ssh");
-      cmd
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .arg("-x")
-        .arg("-N")
-        .arg("-T")
-        .arg("-o")
-        .arg("ExitOnForwardFailure=yes")
-        .arg("-o")
-        .arg("ClearAllForwardings=yes")
-        .arg("-o")
-        .arg("ConnectTimeout=5")
-        .arg("-W")
-        .arg(format!("{}:{}", opts.host_addr, opts.host_port));
-      if let Some(ref user) = opts.ssh_user {
-        cmd.arg("-l").arg(user);
+      Socket::from(s)
+    }
+    // The child streams always go through the handshake
+    Tunneled(ref tunnel) => {
+      let cmd = ssh::tunnel_command(tunnel)?;
+      crate::info!("running {}", CommandLine(&cmd));
+      return Ok(Socket::Child(ChildStream::spawn(cmd, "ssh", timeout)?));
+    }
+    Command(ref command) => {
+      let cmd = command.command();
+      crate::info!("running {}", CommandLine(&cmd));
+      return Ok(Socket::Child(ChildStream::spawn(
+        cmd,
+        command.program(),
+        timeout,
+      )?));
+    }
+    Proxied(ref proxied) => Socket::from(proxy::connect(
+      &proxied.proxy,
+      &proxied.host_addr,
+      proxied.host_port,
+      timeout,
+    )?),
+  };
+  if opts.handshake {
+    if let Socket::TcpStream(ref stream) = socket {
+      tcp_handshake(stream, timeout)?;
+    }
+  }
+  Ok(socket)
+}
+
+/// Sends the handshake request over the stream and waits for a response.
+fn tcp_handshake(
+  stream: &TcpStream,
+  timeout: time::Duration,
+) -> Result<(), io::Error> {
+  stream.set_read_timeout(Some(timeout))?;
+  let mut writer = stream;
+  writer.write_all(HANDSHAKE_REQUEST)?;
+  writer.flush()?;
+  log::wire(true, HANDSHAKE_REQUEST);
+  let mut reader = BufReader::new(stream);
+  read_handshake_response(&mut reader).map_err(|err| {
+    if err.kind() == io::ErrorKind::WouldBlock {
+      io::Error::new(io::ErrorKind::TimedOut, "no response to handshake")
+    } else {
+      err
+    }
+  })?;
+  // The server does not send anything unprompted; should it do so we would
+  // lose the data in the buffer
+  if !reader.buffer().is_empty() {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      "unexpected data after handshake",
+    ));
+  }
+  stream.set_read_timeout(None)
+}
+
+impl ChildStream {
+  /// Spawns the child and verifies that there is an nREPL server answering on
+  /// the other end of its stdio.
+  ///
+  /// Failures to form the connection are reported as refused connections so
+  /// that the caller can move on to the next route.  The error carries the
+  /// child's diagnostics (stderr) with it.
+  fn spawn(
+    mut cmd: Command,
+    name: &str,
+    timeout: time::Duration,
+  ) -> Result<Self, io::Error> {
+    let mut child = cmd.spawn()?;
+    let diagnostics = Diagnostics::capture(&mut child);
+    match handshake(&mut child, timeout) {
+      Ok(stdout) => {
+        diagnostics.pass_through();
+        Ok(Self { child, stdout })
+      }
+      Err(err) => {
+        let _ = child.kill();
+        let status = child.wait()?;
+        let details = diagnostics.collect();
+        Err(io::Error::new(
+          io::ErrorKind::ConnectionRefused,
+          if details.is_empty() {
+            format!("{} failed ({}): {}", name, status, err)
+          } else {
+            format!("{} failed ({}): {}", name, status, details)
+          },
+        ))
+      }
+    }
+  }
+}
+
+/// Sends the handshake request through the child and waits for a response.
+fn handshake(
+  child: &mut Child,
+  timeout: time::Duration,
+) -> Result<BufReader<ChildStdout>, io::Error> {
+  let stdin = child
+    .stdin
+    .as_mut()
+    .expect("child process's stdin is piped");
+  stdin.write_all(HANDSHAKE_REQUEST)?;
+  stdin.flush()?;
+  log::wire(true, HANDSHAKE_REQUEST);
+  let stdout = child
+    .stdout
+    .take()
+    .expect("child process's stdout is piped");
+  let (tx, rx) = mpsc::channel();
+  // We read in a separate thread in order to be able to time out.  Should that
+  // happen the caller kills the child which, in turn, unblocks the reader.
+  thread::spawn(move || {
+    let mut reader = BufReader::new(stdout);
+    let _ = tx.send(read_handshake_response(&mut reader).map(|_| reader));
+  });
+  match rx.recv_timeout(timeout) {
+    Ok(result) => result,
+    Err(_) => Err(io::Error::new(
+      io::ErrorKind::TimedOut,
+      "no response to handshake",
+    )),
+  }
+}
+
+/// Consumes exactly one bencoded response from the reader leaving anything
+/// that follows it in the buffer.
+fn read_handshake_response(reader: &mut impl BufRead) -> io::Result<()> {
+  let mut received = Vec::new();
+  loop {
+    let available = reader.fill_buf()?;
+    if available.is_empty() {
+      return Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed during handshake",
+      ));
+    }
+    let previous_len = received.len();
+    let available_len = available.len();
+    received.extend_from_slice(available);
+    match bencode::scan_next(&received) {
+      Ok((bencode::ObjType::Dictionary, len)) => {
+        log::wire(false, &received[..len]);
+        reader.consume(len - previous_len);
+        return Ok(());
       }
-      if let Some(ref port) = opts.ssh_port {
-        cmd.arg("-p").arg(port.to_string());
+      Err(bencode::Error::UnexpectedEnd) => reader.consume(available_len),
+      Ok(_) | Err(bencode::Error::BadInput) => {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          "bad response to handshake",
+        ))
       }
-      cmd.arg(opts.ssh_addr.to_string());
-      //
-      // XXX(soija) Here we are content with just being able to spawn the
-      //            child process successfully and don't verify that a
-      //            forwarded connection is actually formed.
-      //
-      //            This is okay for now as a failing ssh client seems to
-      //            cause a decent enough error when we try to read from
-      //            or write to the socket on this side.  However, this
-      //            needs to be solved somehow before it is possible to
-      //            knock multiple ports (in case I want to retain that
-      //            feature).
-      //
-      Ok(Socket::SshClient(cmd.spawn()?))
     }
   }
 }
+
+/// Collects the child's stderr in the background until the connection is
+/// formed and passes it through to ours after that
+#[derive(Debug)]
+struct Diagnostics {
+  /// `None` once passing through
+  buffer: Arc<Mutex<Option<Vec<u8>>>>,
+  done: mpsc::Receiver<()>,
+}
+
+impl Diagnostics {
+  fn capture(child: &mut Child) -> Self {
+    let buffer = Arc::new(Mutex::new(Some(Vec::new())));
+    let (tx, done) = mpsc::channel();
+    if let Some(mut stderr) = child.stderr.take() {
+      let buffer = buffer.clone();
+      thread::spawn(move || {
+        let mut chunk = [0_u8; 1024];
+        while let Ok(len) = stderr.read(&mut chunk) {
+          if len == 0 {
+            break;
+          }
+          match *buffer.lock().unwrap() {
+            Some(ref mut buffer) => buffer.extend_from_slice(&chunk[..len]),
+            None => {
+              let _ = io::stderr().write_all(&chunk[..len]);
+            }
+          }
+        }
+        let _ = tx.send(());
+      });
+    }
+    Self { buffer, done }
+  }
+
+  /// Returns the diagnostics collected so far, giving the child a moment to
+  /// finish writing them.
+  fn collect(self) -> String {
+    let _ = self.done.recv_timeout(DIAGNOSTICS_TIMEOUT);
+    let buffer = self.buffer.lock().unwrap();
+    let buffer = buffer.as_deref().unwrap_or_default();
+    String::from_utf8_lossy(buffer).trim().replace('\n', "; ")
+  }
+
+  /// Writes out what has been collected so far and stops collecting, so that
+  /// the warnings of a working connection reach the user.
+  fn pass_through(self) {
+    if let Some(buffer) = self.buffer.lock().unwrap().take() {
+      let _ = io::stderr().write_all(&buffer);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    conn_expr::ConnectionExpr,
+    host_options::{HostOptions, HostOptionsTable, SshOptions},
+    routes::resolve_routes,
+  };
+  use std::process::Stdio;
+
+  #[test]
+  fn handshake_response_leaves_trailing_input() {
+    let mut reader = BufReader::with_capacity(4, &b"d2:id1:xei42e"[..]);
+    read_handshake_response(&mut reader).unwrap();
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "i42e");
+  }
+
+  #[test]
+  fn handshake_rejects_non_nrepl_response() {
+    let mut reader = BufReader::new(&b"SSH-2.0-OpenSSH_9.6\r\n"[..]);
+    let err = read_handshake_response(&mut reader).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+  }
+
+  fn routes(conn_expr: &str, command: Option<&str>) -> Routes {
+    let mut table = HostOptionsTable::default();
+    table.insert(
+      "host".to_owned(),
+      HostOptions {
+        name: None,
+        conn_expr: conn_expr.parse().unwrap(),
+        ask_confirmation: None,
+        assertions: vec![],
+        ssh: SshOptions::default(),
+        command: command.map(|c| c.to_owned()),
+        proxy: None,
+      },
+    );
+    resolve_routes(
+      &ConnectionExpr::HostKey("host".to_owned()),
+      &table,
+      &SshOptions::default(),
+    )
+    .unwrap()
+  }
+
+  fn unused_port() -> u16 {
+    net::TcpListener::bind("127.0.0.1:0")
+      .unwrap()
+      .local_addr()
+      .unwrap()
+      .port()
+  }
+
+  #[test]
+  fn connects_to_first_route_that_answers() {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let conn_expr = format!("127.0.0.1:{},{}", unused_port(), port);
+    let connected =
+      connect_any(routes(&conn_expr, None), &ConnectOptions::default());
+    assert!(matches!(
+      connected,
+      Ok((Socket::TcpStream(_), Route::Direct(addr))) if addr.port() == port
+    ));
+  }
+
+  #[test]
+  fn round_trip_through_command_route() {
+    // `cat` echoes the handshake back and then everything else too
+    let (mut socket, route) =
+      connect_any(routes("1", Some("cat")), &ConnectOptions::default())
+        .unwrap();
+    assert!(matches!(route, Route::Command(_)));
+    let writer = socket.borrow_mut_write();
+    writer.write_all(b"d2:op8:describee").unwrap();
+    writer.flush().unwrap();
+    let mut echoed = [0_u8; 16];
+    socket.borrow_mut_read().read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"d2:op8:describee");
+  }
+
+  #[test]
+  fn attempt_times_out() {
+    let started = time::Instant::now();
+    let opts = ConnectOptions {
+      timeout: time::Duration::from_millis(500),
+      ..Default::default()
+    };
+    // How the attempt fails depends on the timing; what matters is that it
+    // gives up at the deadline
+    assert!(connect_any(routes("1", Some("sleep 5")), &opts).is_err());
+    let elapsed = started.elapsed();
+    assert!(elapsed >= time::Duration::from_millis(500));
+    assert!(elapsed < time::Duration::from_secs(3));
+  }
+
+  #[test]
+  fn retries_until_server_is_up() {
+    let port = unused_port();
+    let server = thread::spawn(move || {
+      thread::sleep(time::Duration::from_millis(600));
+      let listener = net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+      let _ = listener.accept();
+    });
+    let opts = ConnectOptions {
+      retry_until: Some(time::Instant::now() + time::Duration::from_secs(10)),
+      ..Default::default()
+    };
+    let conn_expr = format!("127.0.0.1:{}", port);
+    assert!(connect(routes(&conn_expr, None), &opts).is_ok());
+    server.join().unwrap();
+  }
+
+  #[test]
+  fn tcp_handshake_with_and_without_server() {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+      // The first client gets the request echoed back, the second nothing
+      let (mut client, _) = listener.accept().unwrap();
+      let mut request = [0_u8; HANDSHAKE_REQUEST.len()];
+      client.read_exact(&mut request).unwrap();
+      client.write_all(&request).unwrap();
+      let (silent_client, _) = listener.accept().unwrap();
+      thread::sleep(time::Duration::from_secs(2));
+      drop(silent_client);
+    });
+    let timeout = time::Duration::from_millis(500);
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    assert!(tcp_handshake(&stream, timeout).is_ok());
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let err = tcp_handshake(&stream, timeout).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    server.join().unwrap();
+  }
+
+  fn piped(program: &str, args: &[&str]) -> Command {
+    let mut cmd = Command::new(program);
+    cmd
+      .args(args)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped());
+    cmd
+  }
+
+  #[test]
+  fn child_stream_answering_handshake() {
+    // `cat` echoes the request back which passes for a response
+    assert!(ChildStream::spawn(
+      piped("cat", &[]),
+      "cat",
+      DEFAULT_CONNECT_TIMEOUT
+    )
+    .is_ok());
+  }
+
+  #[test]
+  fn diagnostics_stop_collecting_when_passing_through() {
+    let mut child = piped("sh", &["-c", "echo warning >&2; sleep 1"])
+      .spawn()
+      .unwrap();
+    let diagnostics = Diagnostics::capture(&mut child);
+    let buffer = diagnostics.buffer.clone();
+    diagnostics.pass_through();
+    assert!(buffer.lock().unwrap().is_none());
+    child.wait().unwrap();
+  }
+
+  #[test]
+  fn failing_child_stream_is_refused_with_diagnostics() {
+    let cmd = piped("sh", &["-c", "echo 'no route to host' >&2; exit 255"]);
+    let err =
+      ChildStream::spawn(cmd, "ssh", DEFAULT_CONNECT_TIMEOUT).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    assert!(err.to_string().contains("no route to host"), "{}", err);
+  }
+}
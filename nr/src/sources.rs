@@ -15,35 +15,255 @@
 
 use std::{
   borrow::Cow,
-  collections::HashMap,
   fs,
   io::{self, Read},
   rc::Rc,
 };
 
-use crate::{cli, error::Error};
+use crate::{
+  assertions::HostAssertion,
+  cli,
+  clojure::lex::{self, Lexeme as L},
+  error::Error,
+  prompt::Prompt,
+  template::{ArgSpec, Bindings, Template},
+};
 
 #[derive(Debug)]
 pub struct Source {
   pub content: String,
   pub file: Option<String>,
+  /// The line of the file the content starts on
+  pub line: usize,
+  pub host_assertions: Vec<HostAssertion>,
+}
+
+/// A top-level form of a source as it would be sent
+#[derive(Debug, PartialEq)]
+pub struct Form<'a> {
+  pub code: &'a str,
+  pub file: Option<&'a str>,
+  pub line: usize,
+  /// The namespace the form would be evaluated in
+  pub ns: Rc<str>,
+}
+
+/// Splits the sources into top-level forms following the namespace changes.
+///
+/// The sources are evaluated in the same session, so the namespace set by one
+/// carries over to the next ones.
+pub fn forms(sources: &[Source]) -> Vec<Form<'_>> {
+  let mut ns: Rc<str> = Rc::from("user");
+  let mut forms = Vec::new();
+  for source in sources.iter() {
+    let content = source.content.as_str();
+    let offset = |s: &str| s.as_ptr() as usize - content.as_ptr() as usize;
+    let Ok(lexemes) = lex::lex(content) else {
+      // Not likely as the templates lex the content too
+      forms.push(Form {
+        code: content,
+        file: source.file.as_deref(),
+        line: source.line,
+        ns: ns.clone(),
+      });
+      continue;
+    };
+    for form in top_level_forms(&lexemes) {
+      let (first, last) = (&form[0], &form[form.len() - 1]);
+      let start = offset(first.source());
+      let end = offset(last.source()) + last.source().len();
+      forms.push(Form {
+        code: &content[start..end],
+        file: source.file.as_deref(),
+        line: source.line + content[..start].matches('\n').count(),
+        ns: ns.clone(),
+      });
+      if let Some(change) = ns_change(form) {
+        ns = change;
+      }
+    }
+  }
+  forms
+}
+
+/// Splits the lexemes into the top-level forms, each from its first to its
+/// last lexeme.
+///
+/// The reader macros, like quotes and metadata, go with the forms they apply
+/// to and so does a discarded form with the form that follows it.
+fn top_level_forms<'l, 'a>(lexemes: &'l [L<'a>]) -> Vec<&'l [L<'a>]> {
+  let mut forms = Vec::new();
+  let mut start = None;
+  let mut depth = 0;
+  // The number of forms still to be read to complete the top-level form
+  let mut needed = 0;
+  for (ix, lexeme) in lexemes.iter().enumerate() {
+    if matches!(lexeme, L::Whitespace { .. } | L::Comment { .. }) {
+      continue;
+    }
+    if needed == 0 {
+      start = Some(ix);
+      needed = 1;
+    }
+    match lexeme {
+      L::StartList { .. }
+      | L::StartVector { .. }
+      | L::StartSet { .. }
+      | L::StartMap { .. }
+      | L::StartAnonymousFn { .. }
+      | L::StartReaderConditional { .. } => depth += 1,
+      L::EndList { .. }
+      | L::EndVector { .. }
+      | L::EndSet { .. }
+      | L::EndMap { .. }
+      | L::EndAnonymousFn { .. }
+      | L::EndReaderConditional { .. } => {
+        depth -= 1;
+        if depth == 0 {
+          needed -= 1;
+        }
+      }
+      _ if depth > 0 => (),
+      L::Meta { .. } | L::Discard { .. } | L::TaggedLiteral { .. } => {
+        needed += 1
+      }
+      L::Quote { .. }
+      | L::VarQuote { .. }
+      | L::Synquote { .. }
+      | L::Unquote { .. }
+      | L::SplicingUnquote { .. }
+      | L::SymbolicValuePrefix { .. }
+      | L::ReaderConditionalPrefix { .. }
+      | L::MapQualifier { .. } => (),
+      _ => needed -= 1,
+    }
+    if needed == 0 {
+      if let Some(start) = start.take() {
+        forms.push(&lexemes[start..=ix]);
+      }
+    }
+  }
+  // An incomplete form at the end, like a trailing discard
+  if let Some(start) = start {
+    let end = lexemes
+      .iter()
+      .rposition(|l| !matches!(l, L::Whitespace { .. } | L::Comment { .. }))
+      .expect("the form has a lexeme");
+    forms.push(&lexemes[start..=end]);
+  }
+  forms
+}
+
+/// Returns the namespace the form switches to if it is a `ns` or `in-ns` form.
+fn ns_change(form: &[L]) -> Option<Rc<str>> {
+  let head = form
+    .iter()
+    .filter(|l| !matches!(l, L::Whitespace { .. } | L::Comment { .. }))
+    .take(4)
+    .collect::<Vec<_>>();
+  let is_op =
+    |lexeme: &L, op| matches!(lexeme, L::Symbol { name, .. } if *name == op);
+  let target = match *head {
+    [L::StartList { .. }, op, target, ..] if is_op(op, "ns") => target,
+    [L::StartList { .. }, op, L::Quote { .. }, target]
+      if is_op(op, "in-ns") =>
+    {
+      target
+    }
+    _ => return None,
+  };
+  match *target {
+    L::Symbol {
+      namespace: None,
+      name,
+      ..
+    } => Some(Rc::from(name)),
+    _ => None,
+  }
 }
 
 pub fn load_sources(
   source_args: &[cli::SourceArg],
   template_args: &[cli::TemplateArg],
+  env_prefix: &str,
 ) -> Result<Vec<Source>, Error> {
-  let context = Context::from(template_args);
+  let mut bindings = Bindings::new(template_args).with_env_prefix(env_prefix);
+  let raw_contents = source_args
+    .iter()
+    .map(load_content)
+    .collect::<Result<Vec<_>, _>>()?;
+  let templates = raw_contents
+    .iter()
+    .map(|(file, raw_content)| Template::parse(origin(file), raw_content))
+    .collect::<Result<Vec<_>, _>>()?;
+  ask_missing(&raw_contents, &templates, &mut bindings)?;
   let mut result = Vec::new();
-  for source_arg in source_args.iter() {
-    let (file, raw_content) = load_content(source_arg)?;
-    let content = render_source(&context, raw_content.as_ref());
-    result.push(Source { content, file });
+  for ((file, raw_content), template) in
+    raw_contents.iter().zip(templates.iter())
+  {
+    let host_assertions =
+      header_directives(file.as_deref(), raw_content.as_ref())?;
+    let (content, line) = render_source(origin(file), template, &mut bindings)?;
+    result.push(Source {
+      content,
+      file: file.clone(),
+      line,
+      host_assertions,
+    });
   }
+  bindings.check_all_used()?;
   Ok(result)
 }
 
-fn load_content(
+fn origin(file: &Option<String>) -> &str {
+  file.as_deref().unwrap_or("<input>")
+}
+
+/// Asks the user for the template arguments that have no value when run on a
+/// terminal and otherwise fails listing all of them.
+fn ask_missing(
+  raw_contents: &[(Option<String>, Cow<'_, str>)],
+  templates: &[Template],
+  bindings: &mut Bindings,
+) -> Result<(), Error> {
+  let mut missing: Vec<(&ArgSpec, usize, &str)> = Vec::new();
+  for ((file, _), template) in raw_contents.iter().zip(templates.iter()) {
+    for (spec, line) in template.missing(bindings) {
+      if !missing.iter().any(|(s, _, _)| s.key == spec.key) {
+        missing.push((spec, line, origin(file)));
+      }
+    }
+  }
+  let missing_error = |&(spec, line, origin): &(&ArgSpec, usize, &str)| {
+    Error::MissingTemplateArgument(origin.to_owned(), line, spec.key.to_owned())
+  };
+  if missing.is_empty() {
+    return Ok(());
+  }
+  if let Some(mut prompt) = Prompt::open() {
+    for m in missing.iter() {
+      match prompt.ask(m.0).map_err(Error::CannotPrompt)? {
+        Some(value) => bindings.provide(m.0.key, value.into()),
+        None => return Err(missing_error(m)),
+      }
+    }
+    return Ok(());
+  }
+  match missing[..] {
+    [ref m] => Err(missing_error(m)),
+    _ => Err(Error::MissingTemplateArguments(
+      missing
+        .iter()
+        .map(|(spec, line, origin)| {
+          format!("{} (line {} of {})", spec.key, line, origin)
+        })
+        .collect::<Vec<_>>()
+        .join(", "),
+    )),
+  }
+}
+
+pub fn load_content(
   source_arg: &cli::SourceArg,
 ) -> Result<(Option<String>, Cow<'_, str>), Error> {
   use cli::SourceArg::*;
@@ -70,84 +290,91 @@ fn load_content(
   }
 }
 
-// XXX(soija) This needs work
-// This rendering has the following limitations:
-// - does not catch '#nr[...]' exprs without value arg (nREPL catches this though)
-// - is not easy to extend supporting '#nr[<var> <default>]'
-// - let alone '#nr[<var-1> ... <var-n> <default>]'
-// - does not captures values from environment variables (e.g. NR_VAR_1)
-fn render_source(context: &Context, source: &str) -> String {
-  let after_shebang = if source.starts_with("#!") {
-    match source.split_once('\n') {
-      Some((_, remaining)) => remaining,
-      None => "",
+/// Collects the directives from the comment lines at the start of the source.
+fn header_directives(
+  file: Option<&str>,
+  source: &str,
+) -> Result<Vec<HostAssertion>, Error> {
+  let mut directives = Vec::new();
+  for (ix, line) in source.lines().enumerate() {
+    let line = line.trim_start();
+    if ix == 0 && line.starts_with("#!") || line.is_empty() {
+      continue;
     }
-  } else {
-    source
-  }
-  .trim();
-  if let Some(ref regex) = context.regex {
-    let mut fragments = Vec::<Rc<str>>::new();
-    let mut remaining = after_shebang;
-    while let Some(captures) = regex.captures(remaining) {
-      let full_match = captures.get(0).unwrap();
-      let (upto, after) = remaining.split_at(full_match.end());
-      let (before, _) = upto.split_at(full_match.start());
-      fragments.push(before.to_string().into());
-      let value = context
-        .table
-        .get(captures.get(1).unwrap().as_str())
-        .unwrap();
-      fragments.push(value.clone());
-      remaining = after;
+    let Some(comment) = line.strip_prefix(';') else {
+      break;
+    };
+    match HostAssertion::parse_directive(comment.trim_start_matches(';')) {
+      Some(Ok(directive)) => directives.push(directive),
+      Some(Err(_)) => {
+        return Err(Error::BadScriptDirective(
+          file.unwrap_or("<input>").to_owned(),
+          ix + 1,
+        ))
+      }
+      None => (),
     }
-    fragments.push(remaining.to_string().into());
-    fragments.join("")
-  } else {
-    after_shebang.into()
   }
+  Ok(directives)
 }
 
-#[derive(Debug)]
-struct Context {
-  table: HashMap<Rc<str>, Rc<str>>,
-  regex: Option<regex::Regex>,
+/// Renders the source leaving out the shebang line and the surrounding
+/// whitespace.  Returns also the line the result starts on.
+fn render_source(
+  origin: &str,
+  template: &Template,
+  bindings: &mut Bindings,
+) -> Result<(String, usize), Error> {
+  let rendered = template.render(origin, bindings)?;
+  let after_shebang = if rendered.starts_with("#!") {
+    match rendered.split_once('\n') {
+      Some((_, remaining)) => remaining,
+      None => "",
+    }
+  } else {
+    &rendered
+  };
+  let content = after_shebang.trim();
+  let start = content.as_ptr() as usize - rendered.as_ptr() as usize;
+  Ok((
+    content.to_owned(),
+    1 + rendered[..start].matches('\n').count(),
+  ))
 }
 
-impl From<&[cli::TemplateArg]> for Context {
-  fn from(template_args: &[cli::TemplateArg]) -> Self {
-    let table = template_args.iter().fold(HashMap::new(), |mut m, a| {
-      if let Some(ref n) = a.name {
-        m.insert(n.clone(), a.value.clone());
-      }
-      if let Some(i) = a.pos {
-        m.insert((i + 1).to_string().into(), a.value.clone());
-      }
-      m
-    });
-    let regex = if table.is_empty() {
-      None
-    } else {
-      let keys = table
-        .keys()
-        .map(|s| regex::escape(s))
-        .collect::<Vec<String>>();
-      let key_union = keys.join("|");
-      let pat = format!(r#"#nr\s*\[\s*({})\s*\]"#, key_union,);
-      // XXX(soija) Once https://github.com/rust-lang/rust/issues/79524 lands, use
-      // intersperse.
-      /*
-      let pat = format!(
-          r#"#nr\s*\[\s*({})\s*\]"#,
-          table
-              .keys()
-              .map(|k| regex::escape(k))
-              .intersperse("|".to_string())
-              .collect::<String>(),
-      );
-      */
-      Some(regex::Regex::new(&pat).unwrap())
-    };
-    Self { table, regex }
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn source(content: &str, line: usize) -> Source {
+    Source {
+      content: content.to_owned(),
+      file: Some("x.clj".to_owned()),
+      line,
+      host_assertions: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn splits_forms_following_namespace() {
+    let sources = [
+      source("(ns a.b) ; comment\n\n^:m (f \\a)\n#\"re\"", 3),
+      source("(in-ns 'c) (g #_x y) #_(z)\n#inst \"2024\"", 1),
+    ];
+    let forms = forms(&sources)
+      .into_iter()
+      .map(|form| (form.code, form.line, form.ns.to_string()))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      forms,
+      [
+        ("(ns a.b)", 3, "user".to_owned()),
+        ("^:m (f \\a)", 5, "a.b".to_owned()),
+        ("#\"re\"", 6, "a.b".to_owned()),
+        ("(in-ns 'c)", 1, "a.b".to_owned()),
+        ("(g #_x y)", 1, "c".to_owned()),
+        ("#_(z)\n#inst \"2024\"", 1, "c".to_owned()),
+      ]
+    );
   }
 }
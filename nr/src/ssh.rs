@@ -0,0 +1,364 @@
+// ssh.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Running the OpenSSH client for tunneling.
+
+use std::{
+  env,
+  ffi::OsString,
+  fs, io,
+  os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+  path::{Path, PathBuf},
+  process::{Command, Stdio},
+};
+
+use crate::{
+  error::Error,
+  host_options::SshOptions,
+  routes::{JumpHost, TunnelOptions},
+};
+
+/// Seconds an idle control master lingers unless told otherwise
+const DEFAULT_CONTROL_PERSIST: u64 = 300;
+
+/// The oldest supported OpenSSH version; `-J` appeared in 7.3.
+const MIN_OPENSSH_VERSION: (u16, u16) = (7, 3);
+const MIN_OPENSSH_VERSION_STR: &str = "7.3";
+
+/// Checks that the `ssh` command is an OpenSSH client that we support.
+pub fn check_client() -> Result<(), Error> {
+  let output = Command::new("ssh")
+    .arg("-V")
+    .stdin(Stdio::null())
+    .output()
+    .map_err(Error::SshClientNotFound)?;
+  // OpenSSH prints its version on stderr
+  let banner = String::from_utf8_lossy(if output.stderr.is_empty() {
+    &output.stdout
+  } else {
+    &output.stderr
+  })
+  .trim()
+  .to_owned();
+  match parse_openssh_version(&banner) {
+    Some(version) if version >= MIN_OPENSSH_VERSION => Ok(()),
+    _ => Err(Error::UnsupportedSshClient(banner, MIN_OPENSSH_VERSION_STR)),
+  }
+}
+
+fn parse_openssh_version(banner: &str) -> Option<(u16, u16)> {
+  let version = banner.strip_prefix("OpenSSH_")?;
+  let (major, rest) = version.split_once('.')?;
+  let minor = rest
+    .find(|c: char| !c.is_ascii_digit())
+    .map(|end| &rest[..end])
+    .unwrap_or(rest);
+  Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// Builds the ssh command that forwards its stdio to the tunneled host.
+pub fn tunnel_command(opts: &TunnelOptions) -> Result<Command, io::Error> {
+  let mut cmd = Command::new("ssh");
+  cmd
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .arg("-x")
+    .arg("-N")
+    .arg("-T")
+    .arg("-o")
+    .arg("ExitOnForwardFailure=yes")
+    .arg("-o")
+    .arg("ClearAllForwardings=yes")
+    .arg("-o")
+    .arg("ConnectTimeout=5");
+  if opts.ssh_options.control_master.unwrap_or(false) {
+    let dir = ensure_control_dir()?;
+    add_control_master_options(&mut cmd, &opts.ssh_options, &dir);
+  }
+  add_host_options(&mut cmd, &opts.ssh_options);
+  add_jump_hosts(&mut cmd, opts);
+  cmd
+    .arg("-W")
+    .arg(format!("{}:{}", opts.host_addr, opts.host_port));
+  if let Some(ref user) = opts.ssh_user {
+    cmd.arg("-l").arg(user);
+  }
+  if let Some(ref port) = opts.ssh_port {
+    cmd.arg("-p").arg(port.to_string());
+  }
+  cmd.arg(opts.ssh_addr.to_string());
+  Ok(cmd)
+}
+
+/// Adds the options for reusing the connection through a control master whose
+/// socket is in the directory.
+fn add_control_master_options(
+  cmd: &mut Command,
+  opts: &SshOptions,
+  control_dir: &Path,
+) {
+  // The `%C` token expands to a hash of the connection parameters and keeps
+  // the path short enough for a Unix domain socket.
+  let path = control_dir.join("%C");
+  cmd
+    .arg("-o")
+    .arg("ControlMaster=auto")
+    .arg("-o")
+    .arg(format!("ControlPath={}", path.to_string_lossy()))
+    .arg("-o")
+    .arg(format!(
+      "ControlPersist={}",
+      opts.control_persist.unwrap_or(DEFAULT_CONTROL_PERSIST)
+    ));
+}
+
+fn add_host_options(cmd: &mut Command, opts: &SshOptions) {
+  cmd.args(host_option_args(opts));
+}
+
+/// Returns the arguments the host options (other than the jump hosts) turn
+/// into on the ssh command line.
+pub fn host_option_args(opts: &SshOptions) -> Vec<OsString> {
+  let mut args = Vec::<OsString>::new();
+  if let Some(ref path) = opts.config_file {
+    args.push("-F".into());
+    args.push(expand_tilde(path).into());
+  }
+  if let Some(ref path) = opts.identity_file {
+    args.push("-i".into());
+    args.push(expand_tilde(path).into());
+  }
+  if let Some(policy) = opts.strict_host_key_checking {
+    args.push("-o".into());
+    args.push(format!("StrictHostKeyChecking={}", policy).into());
+  }
+  for (key, value) in opts.extra_options.iter() {
+    args.push("-o".into());
+    args.push(format!("{}={}", key, value).into());
+  }
+  args
+}
+
+/// Adds the jump hosts of the host options followed by the ones given in the
+/// connection expression.
+fn add_jump_hosts(cmd: &mut Command, opts: &TunnelOptions) {
+  let jump_hosts = opts
+    .ssh_options
+    .proxy_jump
+    .iter()
+    .cloned()
+    .chain(opts.jump_hosts.iter().map(JumpHost::to_string))
+    .collect::<Vec<_>>();
+  if !jump_hosts.is_empty() {
+    cmd.arg("-J").arg(jump_hosts.join(","));
+  }
+}
+
+fn expand_tilde(path: &Path) -> PathBuf {
+  match (path.strip_prefix("~"), env::var_os("HOME")) {
+    (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+    _ => path.to_owned(),
+  }
+}
+
+/// Returns the private directory holding the control sockets.
+///
+/// This is `${XDG_RUNTIME_DIR}/nreplops/ssh`, if the runtime directory is
+/// defined, or `${HOME}/.nreplops/ssh` otherwise.
+fn control_dir() -> Result<PathBuf, io::Error> {
+  let mut dir = if let Some(dir) = env::var_os("XDG_RUNTIME_DIR")
+    .map(PathBuf::from)
+    .filter(|p| p.is_absolute())
+  {
+    dir.join("nreplops")
+  } else if let Some(dir) = env::var_os("HOME")
+    .map(PathBuf::from)
+    .filter(|p| p.is_absolute())
+  {
+    dir.join(".nreplops")
+  } else {
+    return Err(io::Error::new(
+      io::ErrorKind::NotFound,
+      "neither XDG_RUNTIME_DIR nor HOME is set",
+    ));
+  };
+  dir.push("ssh");
+  Ok(dir)
+}
+
+fn ensure_control_dir() -> Result<PathBuf, io::Error> {
+  let dir = control_dir()?;
+  fs::DirBuilder::new()
+    .recursive(true)
+    .mode(0o700)
+    .create(&dir)?;
+  // Tighten the permissions in case the directory was already there
+  fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+  Ok(dir)
+}
+
+/// Asks all the control masters to exit and returns how many there were.
+pub fn close_control_masters() -> Result<usize, Error> {
+  let dir = control_dir().map_err(Error::CannotCloseSshControlMasters)?;
+  let entries = match fs::read_dir(&dir) {
+    Ok(entries) => entries,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+    Err(e) => return Err(Error::CannotCloseSshControlMasters(e)),
+  };
+  let mut count = 0;
+  for entry in entries {
+    let entry = entry.map_err(Error::CannotCloseSshControlMasters)?;
+    let is_socket = entry
+      .file_type()
+      .map_err(Error::CannotCloseSshControlMasters)?
+      .is_socket();
+    if !is_socket {
+      continue;
+    }
+    // The destination is irrelevant as the control path is given explicitly
+    let status = Command::new("ssh")
+      .stdin(Stdio::null())
+      .stdout(Stdio::null())
+      .stderr(Stdio::null())
+      .arg("-o")
+      .arg(format!("ControlPath={}", entry.path().to_string_lossy()))
+      .arg("-O")
+      .arg("exit")
+      .arg("nreplops-control-master")
+      .status()
+      .map_err(Error::CannotCloseSshControlMasters)?;
+    if status.success() {
+      count += 1;
+    } else {
+      // A stale socket left behind by a master that is gone already
+      let _ = fs::remove_file(entry.path());
+    }
+  }
+  Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::host_options::StrictHostKeyChecking;
+
+  #[test]
+  fn plain_tunnel_command() {
+    let opts = TunnelOptions {
+      ssh_user: Some("alice".to_owned()),
+      ssh_addr: "bastion".parse().unwrap(),
+      ssh_port: Some(2222),
+      jump_hosts: vec![],
+      host_addr: "app".parse().unwrap(),
+      host_port: 7888,
+      ssh_options: SshOptions::default(),
+    };
+    let cmd = tunnel_command(&opts).unwrap();
+    let args = cmd
+      .get_args()
+      .map(|a| a.to_str().unwrap())
+      .collect::<Vec<_>>();
+    assert!(!args.iter().any(|a| a.starts_with("Control")));
+    assert!(args
+      .ends_with(&["-W", "app:7888", "-l", "alice", "-p", "2222", "bastion"]));
+  }
+
+  #[test]
+  fn control_master_options() {
+    let opts = SshOptions {
+      control_master: Some(true),
+      control_persist: Some(60),
+      ..Default::default()
+    };
+    // Only the path is used; nothing is created
+    let mut cmd = Command::new("ssh");
+    add_control_master_options(&mut cmd, &opts, Path::new("/run/nr-test"));
+    let args = cmd
+      .get_args()
+      .map(|a| a.to_str().unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      args,
+      [
+        "-o",
+        "ControlMaster=auto",
+        "-o",
+        "ControlPath=/run/nr-test/%C",
+        "-o",
+        "ControlPersist=60"
+      ]
+    );
+  }
+
+  #[test]
+  fn tunnel_command_with_host_options() {
+    let opts = TunnelOptions {
+      ssh_user: None,
+      ssh_addr: "app".parse().unwrap(),
+      ssh_port: None,
+      jump_hosts: vec![JumpHost {
+        user: Some("bob".to_owned()),
+        addr: "[::1]".parse().unwrap(),
+        port: Some(2222),
+      }],
+      host_addr: "localhost".parse().unwrap(),
+      host_port: 7888,
+      ssh_options: SshOptions {
+        identity_file: Some("/keys/id".into()),
+        proxy_jump: Some("bastion".to_owned()),
+        strict_host_key_checking: Some(StrictHostKeyChecking::No),
+        config_file: Some("/etc/nr_ssh_config".into()),
+        extra_options: [("ServerAliveInterval".to_owned(), "30".to_owned())]
+          .into(),
+        ..Default::default()
+      },
+    };
+    let cmd = tunnel_command(&opts).unwrap();
+    let args = cmd
+      .get_args()
+      .map(|a| a.to_str().unwrap())
+      .collect::<Vec<_>>();
+    assert!(args.ends_with(&[
+      "-F",
+      "/etc/nr_ssh_config",
+      "-i",
+      "/keys/id",
+      "-o",
+      "StrictHostKeyChecking=no",
+      "-o",
+      "ServerAliveInterval=30",
+      "-J",
+      "bastion,bob@[::1]:2222",
+      "-W",
+      "localhost:7888",
+      "app"
+    ]));
+  }
+
+  #[test]
+  fn parse_openssh_versions() {
+    assert_eq!(
+      parse_openssh_version("OpenSSH_9.6p1 Ubuntu-3ubuntu13, OpenSSL 3.0.13"),
+      Some((9, 6))
+    );
+    assert_eq!(
+      parse_openssh_version("OpenSSH_7.3p1, LibreSSL"),
+      Some((7, 3))
+    );
+    assert_eq!(parse_openssh_version("OpenSSH_10.0"), Some((10, 0)));
+    assert_eq!(parse_openssh_version("dropbear v2022.83"), None);
+  }
+}
@@ -0,0 +1,1163 @@
+// template.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Filling in the `#nr[...]` placeholders of the sources.
+//!
+//! The placeholders are tagged literals and are recognized with the Clojure
+//! lexer, so that a `#nr[...]` within a string or a comment is left alone.  A
+//! placeholder starts with the name or the position of the argument and may
+//! go on with directives:
+//!
+//! - `:or <form>` gives the default value
+//! - `:or-void` makes the placeholder vanish when there is no value
+//! - `:env <var>` takes the value from the environment variable, if set
+//! - `:name <name>` and `:help "<text>"` document the argument
+//! - `:str`, `:clj`, `:int`, and `:edn` tell how to interpret the value
+//! - `:secret` keeps the value out of the logs
+//!
+//! Without a type directive the numbers, keywords, and booleans are inserted
+//! as they are and anything else as a string.
+//!
+//! The directives are about the argument rather than the placeholder; given
+//! once, they apply to all the placeholders of the argument in the source.
+//!
+//! The rest argument `#nr[& <name>]` takes the positional arguments after the
+//! last numbered one and fills in the placeholder with a vector of them, each
+//! interpreted as above.
+
+use std::{collections::HashMap, env, ops::Range, rc::Rc};
+
+use crate::{
+  cli::TemplateArg,
+  clojure::{
+    lex::{self, Lexeme as L, NumericValue},
+    literal,
+  },
+  error::Error,
+  log,
+};
+
+/// What the argument falls back to when it is not given
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fallback<'a> {
+  /// The source of the default form
+  Value(&'a str),
+  Void,
+}
+
+/// How the value of the argument is put into the source
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgType {
+  /// As a string literal
+  Str,
+  /// As it is, provided that it is Clojure code
+  Clj,
+  /// As it is, provided that it is an integer
+  Int,
+  /// As quoted data, provided that it is a single EDN value
+  Edn,
+}
+
+impl ArgType {
+  fn directive(self) -> &'static str {
+    match self {
+      ArgType::Str => ":str",
+      ArgType::Clj => ":clj",
+      ArgType::Int => ":int",
+      ArgType::Edn => ":edn",
+    }
+  }
+
+  /// Checks the Clojure data given as the value, quoting it when it needs to
+  /// be, or tells what was expected instead.
+  fn accept(this: Option<Self>, literal: &str) -> Result<String, &'static str> {
+    match this {
+      // Symbols and collections are data, not code, so they are quoted like
+      // `:edn` does
+      None => match lex::lex(literal).ok().as_deref() {
+        Some(
+          [L::Nil { .. }
+          | L::Boolean { .. }
+          | L::Numeric { .. }
+          | L::Char { .. }
+          | L::String { .. }
+          | L::Keyword { .. }]
+          | [L::SymbolicValuePrefix { .. }, L::SymbolicValue { .. }],
+        ) => Ok(literal.to_owned()),
+        _ => Ok(format!("'{}", literal)),
+      },
+      Some(ArgType::Clj) => Ok(literal.to_owned()),
+      Some(ArgType::Str) => match lex::lex(literal).ok().as_deref() {
+        Some([L::String { .. }]) => Ok(literal.to_owned()),
+        _ => Err("a string"),
+      },
+      Some(ArgType::Int | ArgType::Edn) => Self::interpret(this, literal),
+    }
+  }
+
+  /// Turns the value into source code or tells what was expected instead.
+  fn interpret(
+    this: Option<Self>,
+    value: &str,
+  ) -> Result<String, &'static str> {
+    let lexemes = lex::lex(value).ok();
+    let forms = lexemes
+      .iter()
+      .flatten()
+      .filter(|l| l.form_ix().is_some())
+      .collect::<Vec<_>>();
+    // Only the forms go in; a trailing comment would comment out the code
+    // following the placeholder.
+    match this {
+      None => Ok(match forms[..] {
+        [L::Numeric { .. } | L::Keyword { .. } | L::Boolean { .. }] => {
+          span(value, &forms).to_owned()
+        }
+        _ => literal::string(value),
+      }),
+      Some(ArgType::Str) => Ok(literal::string(value)),
+      Some(ArgType::Clj) => match lexemes {
+        Some(ref lexemes) => Ok(
+          span(
+            value,
+            &lexemes
+              .iter()
+              .filter(|l| {
+                !matches!(l, L::Whitespace { .. } | L::Comment { .. })
+              })
+              .collect::<Vec<_>>(),
+          )
+          .to_owned(),
+        ),
+        None => Err("Clojure code"),
+      },
+      Some(ArgType::Int) => match forms[..] {
+        [L::Numeric {
+          value: NumericValue::Int { .. },
+          ..
+        }] => Ok(span(value, &forms).to_owned()),
+        _ => Err("an integer"),
+      },
+      Some(ArgType::Edn) => {
+        let discarded = forms
+          .iter()
+          .filter_map(|l| match l {
+            L::Discard { form_ix, .. } => Some(form_ix.ix),
+            _ => None,
+          })
+          .collect::<Vec<_>>();
+        let mut roots = forms
+          .iter()
+          .filter_map(|l| l.form_ix())
+          .filter(|f| f.parent == 0 && !discarded.contains(&f.ix))
+          .map(|f| f.ix)
+          .collect::<Vec<_>>();
+        roots.dedup();
+        let is_code = forms.iter().any(|l| {
+          matches!(
+            l,
+            L::Quote { .. }
+              | L::VarQuote { .. }
+              | L::Synquote { .. }
+              | L::Unquote { .. }
+              | L::SplicingUnquote { .. }
+              | L::Meta { .. }
+              | L::Regex { .. }
+              | L::StartAnonymousFn { .. }
+              | L::ReaderConditionalPrefix { .. }
+          )
+        });
+        if roots.len() == 1 && !is_code {
+          // The lexemes of the root form and of the forms nested in it
+          let mut within = roots.clone();
+          let root = forms
+            .iter()
+            .filter(|l| {
+              l.form_ix().map_or(false, |f| {
+                if f.ix == roots[0] || within.contains(&f.parent) {
+                  within.push(f.ix);
+                  true
+                } else {
+                  false
+                }
+              })
+            })
+            .copied()
+            .collect::<Vec<_>>();
+          Ok(format!("'{}", span(value, &root)))
+        } else {
+          Err("an EDN value")
+        }
+      }
+    }
+  }
+}
+
+/// Returns the part of the value from the start of the first lexeme to the end
+/// of the last one.
+fn span<'a>(value: &'a str, lexemes: &[&L<'a>]) -> &'a str {
+  let offset = |l: &L| l.source().as_ptr() as usize - value.as_ptr() as usize;
+  match (lexemes.first(), lexemes.last()) {
+    (Some(first), Some(last)) => {
+      &value[offset(first)..offset(last) + last.source().len()]
+    }
+    _ => "",
+  }
+}
+
+/// What the source says about an argument
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ArgSpec<'a> {
+  /// The name or the position (starting from one) of the argument
+  pub key: &'a str,
+  /// Whether the argument takes the remaining positional arguments
+  /// (`#nr[& key]`)
+  pub rest: bool,
+  pub fallback: Option<Fallback<'a>>,
+  pub kind: Option<ArgType>,
+  pub env: Option<String>,
+  pub name: Option<String>,
+  pub help: Option<String>,
+  /// Whether the value is kept out of the logs (the `:secret` directive)
+  pub secret: bool,
+}
+
+impl ArgSpec<'_> {
+  /// Checks that the value is of the type of the argument or tells what was
+  /// expected instead.
+  pub fn check(&self, value: &str) -> Result<(), &'static str> {
+    ArgType::interpret(self.kind, value).map(|_| ())
+  }
+}
+
+/// A placeholder in the source
+#[derive(Debug)]
+struct Placeholder {
+  /// The location of the whole tagged literal in the source
+  range: Range<usize>,
+  line: usize,
+  /// The index of the argument's spec
+  arg: usize,
+}
+
+#[derive(Debug)]
+pub struct Template<'a> {
+  source: &'a str,
+  placeholders: Vec<Placeholder>,
+  args: Vec<ArgSpec<'a>>,
+}
+
+impl<'a> Template<'a> {
+  /// Finds the placeholders in the source.  The origin (the file name or
+  /// similar) is for the error messages only.
+  pub fn parse(origin: &str, source: &'a str) -> Result<Self, Error> {
+    let mut template = Self {
+      source,
+      placeholders: vec![],
+      args: vec![],
+    };
+    let lexemes = match lex::lex(source) {
+      Ok(lexemes) => lexemes,
+      // Leave the syntax errors for the server to report, unless there might
+      // be something to fill in
+      Err(_) if !source.contains("#nr") => return Ok(template),
+      Err(lex::Error::Pest(e)) => {
+        let line = match e.line_col {
+          pest::error::LineColLocation::Pos((line, _)) => line,
+          pest::error::LineColLocation::Span((line, _), _) => line,
+        };
+        return Err(Error::CannotParseSource(origin.to_owned(), line));
+      }
+    };
+    let parents = lexemes
+      .iter()
+      .filter_map(|l| l.form_ix())
+      .map(|f| (f.ix, f.parent))
+      .collect::<HashMap<_, _>>();
+    let discarded = lexemes
+      .iter()
+      .filter_map(|l| match l {
+        L::Discard { form_ix, .. } => Some(form_ix.ix),
+        _ => None,
+      })
+      .collect::<Vec<_>>();
+    let offset = |part: &str| part.as_ptr() as usize - source.as_ptr() as usize;
+    for (ix, lexeme) in lexemes.iter().enumerate() {
+      let L::TaggedLiteral {
+        form_ix,
+        tag_ix,
+        arg_ix,
+        source: prefix,
+      } = lexeme
+      else {
+        continue;
+      };
+      let rest = &lexemes[ix + 1..];
+      if !rest.iter().any(|l| {
+        matches!(
+          l,
+          L::Tag { form_ix, namespace: None, name: "nr", .. }
+            if form_ix.ix == tag_ix.ix
+        )
+      }) {
+        continue;
+      }
+      if discarded.contains(&root(form_ix.ix, &parents)) {
+        continue;
+      }
+      let start = offset(prefix);
+      let line = source[..start].matches('\n').count() + 1;
+      let bad = || Error::BadTemplatePlaceholder(origin.to_owned(), line);
+      // Placeholders within placeholders make no sense
+      if matches!(template.placeholders.last(), Some(p) if start < p.range.end)
+      {
+        return Err(bad());
+      }
+      let open = rest
+        .iter()
+        .position(|l| {
+          matches!(
+            l,
+            L::StartVector { form_ix, .. } if form_ix.ix == arg_ix.ix
+          )
+        })
+        .ok_or_else(bad)?;
+      let close = rest
+        .iter()
+        .position(|l| {
+          matches!(
+            l,
+            L::EndVector { form_ix, .. } if form_ix.ix == arg_ix.ix
+          )
+        })
+        .ok_or_else(bad)?;
+      let (open_end, close_start) = {
+        let open = rest[open].source();
+        (offset(open) + open.len(), offset(rest[close].source()))
+      };
+      let body = &rest[open + 1..close];
+      let mut elements =
+        elements(body, arg_ix.ix, &parents)
+          .into_iter()
+          .map(|(first, last)| {
+            let start = match first {
+              0 => open_end,
+              _ => {
+                let prev = body[first - 1].source();
+                offset(prev) + prev.len()
+              }
+            };
+            let end = match body.get(last + 1) {
+              Some(next) => offset(next.source()),
+              None => close_start,
+            };
+            Element {
+              text: source[start..end].trim(),
+              lexemes: body[first..=last]
+                .iter()
+                .filter(|l| l.form_ix().is_some())
+                .collect(),
+            }
+          });
+
+      let first = elements.next().ok_or_else(bad)?;
+      let rest_arg = first.as_symbol() == Some("&");
+      let key = if rest_arg {
+        elements.next().and_then(|e| e.as_symbol())
+      } else {
+        first.as_symbol().or_else(|| first.as_position())
+      }
+      .ok_or_else(bad)?;
+      let mut spec = ArgSpec {
+        key,
+        rest: rest_arg,
+        ..Default::default()
+      };
+      let conflict = |directive| {
+        Error::ConflictingTemplateDirective(
+          origin.to_owned(),
+          line,
+          directive,
+          key.to_owned(),
+        )
+      };
+      while let Some(directive) = elements.next() {
+        match directive.as_keyword().ok_or_else(bad)? {
+          "or" => {
+            let value = Fallback::Value(elements.next().ok_or_else(bad)?.text);
+            merge(&mut spec.fallback, Some(value))
+              .map_err(|_| conflict(":or"))?;
+          }
+          "or-void" => merge(&mut spec.fallback, Some(Fallback::Void))
+            .map_err(|_| conflict(":or-void"))?,
+          "env" => {
+            let var = elements
+              .next()
+              .and_then(|e| {
+                e.as_symbol().map(Into::into).or_else(|| e.as_string())
+              })
+              .ok_or_else(bad)?;
+            merge(&mut spec.env, Some(var)).map_err(|_| conflict(":env"))?;
+          }
+          "name" => {
+            let name = elements
+              .next()
+              .and_then(|e| {
+                e.as_symbol().map(Into::into).or_else(|| e.as_string())
+              })
+              .ok_or_else(bad)?;
+            merge(&mut spec.name, Some(name)).map_err(|_| conflict(":name"))?;
+          }
+          "help" => {
+            let help = elements
+              .next()
+              .and_then(|e| e.as_string())
+              .ok_or_else(bad)?;
+            merge(&mut spec.help, Some(help)).map_err(|_| conflict(":help"))?;
+          }
+          "secret" => spec.secret = true,
+          "str" | "clj" | "int" | "edn" => {
+            let kind = match directive.as_keyword() {
+              Some("str") => ArgType::Str,
+              Some("clj") => ArgType::Clj,
+              Some("int") => ArgType::Int,
+              _ => ArgType::Edn,
+            };
+            merge(&mut spec.kind, Some(kind))
+              .map_err(|_| conflict(kind.directive()))?;
+          }
+          _ => return Err(bad()),
+        }
+      }
+
+      // The rest argument takes its values from the command line only
+      if spec.rest && spec.env.is_some() {
+        return Err(bad());
+      }
+
+      let arg = match template.args.iter().position(|a| a.key == key) {
+        Some(arg) => {
+          let known = &mut template.args[arg];
+          if known.rest != spec.rest {
+            return Err(bad());
+          }
+          merge(&mut known.fallback, spec.fallback)
+            .map_err(|_| conflict(":or"))?;
+          if let Some(kind) = spec.kind {
+            merge(&mut known.kind, Some(kind))
+              .map_err(|_| conflict(kind.directive()))?;
+          }
+          merge(&mut known.env, spec.env).map_err(|_| conflict(":env"))?;
+          merge(&mut known.name, spec.name).map_err(|_| conflict(":name"))?;
+          merge(&mut known.help, spec.help).map_err(|_| conflict(":help"))?;
+          known.secret |= spec.secret;
+          arg
+        }
+        None => {
+          template.args.push(spec);
+          template.args.len() - 1
+        }
+      };
+      template.placeholders.push(Placeholder {
+        range: start..close_start + 1,
+        line,
+        arg,
+      });
+    }
+    Ok(template)
+  }
+
+  /// Returns what the source says about the arguments in the order of
+  /// appearance.
+  pub fn args(&self) -> &[ArgSpec<'a>] {
+    &self.args
+  }
+
+  /// Returns the arguments that have no value, each with the line where it
+  /// first appears.
+  pub fn missing(&self, bindings: &Bindings) -> Vec<(&ArgSpec<'a>, usize)> {
+    self
+      .args
+      .iter()
+      .enumerate()
+      .filter(|(_, spec)| {
+        !spec.rest
+          && spec.fallback.is_none()
+          && !bindings.has(spec.key)
+          && spec
+            .env
+            .as_ref()
+            .map_or(true, |var| (bindings.env_var)(var).is_none())
+      })
+      .filter_map(|(ix, spec)| {
+        self
+          .placeholders
+          .iter()
+          .find(|p| p.arg == ix)
+          .map(|p| (spec, p.line))
+      })
+      .collect()
+  }
+
+  /// Fills in the placeholders.
+  ///
+  /// The value is taken from the argument, the environment variable (the
+  /// `:env` directive), or the default (the `:or` and `:or-void` directives),
+  /// whichever comes first.  The default is code and goes in as it is; the
+  /// other values are interpreted according to their type.
+  pub fn render(
+    &self,
+    origin: &str,
+    bindings: &mut Bindings,
+  ) -> Result<String, Error> {
+    let mut rendered = String::with_capacity(self.source.len());
+    let mut at = 0;
+    // The rest argument starts after the last positional argument
+    let rest_from = self
+      .args
+      .iter()
+      .filter_map(|spec| spec.key.parse::<usize>().ok())
+      .max()
+      .unwrap_or(0);
+    for placeholder in self.placeholders.iter() {
+      let spec = &self.args[placeholder.arg];
+      // Only the given values are secrets, not the defaults
+      let interpret = |value: &str, literal: bool| {
+        if spec.secret {
+          log::add_secret(value);
+        }
+        if literal {
+          ArgType::accept(spec.kind, value)
+        } else {
+          ArgType::interpret(spec.kind, value)
+        }
+        .map(|rendered| {
+          if spec.secret && rendered != value {
+            log::add_secret(&rendered);
+          }
+          rendered
+        })
+        .map_err(|expected| {
+          Error::BadTemplateArgumentValue(
+            origin.to_owned(),
+            placeholder.line,
+            spec.key.to_owned(),
+            expected,
+          )
+        })
+      };
+      let given = if spec.rest {
+        let values = bindings.rest(rest_from);
+        if values.is_empty() && spec.fallback.is_some() {
+          None
+        } else {
+          let elements = values
+            .iter()
+            .map(|value| interpret(value, false))
+            .collect::<Result<Vec<_>, _>>()?;
+          Some(format!("[{}]", elements.join(" ")))
+        }
+      } else {
+        match bindings.lookup(spec.key) {
+          Some(arg) => Some(interpret(&arg.value, arg.literal)?),
+          None => spec
+            .env
+            .as_ref()
+            .and_then(|var| (bindings.env_var)(var))
+            .map(|value| interpret(&value, false))
+            .transpose()?,
+        }
+      };
+      let value = match (given, &spec.fallback) {
+        (Some(given), _) => given,
+        (None, Some(Fallback::Value(value))) => (*value).to_owned(),
+        (None, Some(Fallback::Void)) => String::new(),
+        (None, None) => {
+          return Err(Error::MissingTemplateArgument(
+            origin.to_owned(),
+            placeholder.line,
+            spec.key.to_owned(),
+          ))
+        }
+      };
+      rendered.push_str(&self.source[at..placeholder.range.start]);
+      rendered.push_str(&value);
+      at = placeholder.range.end;
+    }
+    rendered.push_str(&self.source[at..]);
+    Ok(rendered)
+  }
+}
+
+/// Stores the value unless a different one is there already.
+fn merge<T: PartialEq>(
+  into: &mut Option<T>,
+  value: Option<T>,
+) -> Result<(), ()> {
+  match (into.as_ref(), value) {
+    (Some(known), Some(value)) if *known != value => Err(()),
+    (None, Some(value)) => {
+      *into = Some(value);
+      Ok(())
+    }
+    _ => Ok(()),
+  }
+}
+
+/// Returns the top-level form the form belongs to.
+fn root(mut form: u32, parents: &HashMap<u32, u32>) -> u32 {
+  while let Some(&parent) = parents.get(&form).filter(|&&p| p != 0) {
+    form = parent;
+  }
+  form
+}
+
+/// Returns the first and the last lexeme of each form directly within the
+/// parent form.  Discarded forms are skipped.
+fn elements(
+  body: &[L],
+  parent: u32,
+  parents: &HashMap<u32, u32>,
+) -> Vec<(usize, usize)> {
+  let mut elements = Vec::<(u32, usize, usize)>::new();
+  for (ix, lexeme) in body.iter().enumerate() {
+    let Some(mut form) = lexeme.form_ix().map(|f| f.ix) else {
+      continue;
+    };
+    // Climb up to the form directly within the parent
+    let element = loop {
+      match parents.get(&form) {
+        Some(&p) if p == parent => break Some(form),
+        Some(&p) if p != 0 => form = p,
+        _ => break None,
+      }
+    };
+    match (element, elements.last_mut()) {
+      (Some(e), Some((last, _, end))) if *last == e => *end = ix,
+      (Some(e), _) => elements.push((e, ix, ix)),
+      (None, _) => (),
+    }
+  }
+  elements
+    .into_iter()
+    .map(|(_, first, last)| (first, last))
+    .collect()
+}
+
+/// A form within a placeholder
+struct Element<'s, 'a> {
+  text: &'a str,
+  lexemes: Vec<&'s L<'a>>,
+}
+
+impl<'s, 'a> Element<'s, 'a> {
+  fn as_symbol(&self) -> Option<&'a str> {
+    match self.lexemes[..] {
+      [L::Symbol {
+        namespace: None,
+        name,
+        ..
+      }] => Some(name),
+      _ => None,
+    }
+  }
+
+  fn as_position(&self) -> Option<&'a str> {
+    match self.lexemes[..] {
+      [L::Numeric {
+        value:
+          NumericValue::Int {
+            positive: true,
+            radix: 10,
+            value,
+          },
+        ..
+      }] => Some(value),
+      _ => None,
+    }
+  }
+
+  fn as_keyword(&self) -> Option<&'a str> {
+    match self.lexemes[..] {
+      [L::Keyword {
+        alias: false,
+        namespace: None,
+        name,
+        ..
+      }] => Some(name),
+      _ => None,
+    }
+  }
+
+  fn as_string(&self) -> Option<String> {
+    match self.lexemes[..] {
+      [L::String { ref value, .. }] => Some(literal::string_value(value)),
+      _ => None,
+    }
+  }
+}
+
+/// The template arguments and whether they have been used
+#[derive(Debug)]
+pub struct Bindings<'a> {
+  args: &'a [TemplateArg],
+  used: Vec<bool>,
+  /// The values provided later on, for example, by the user on the terminal
+  provided: Vec<TemplateArg>,
+  /// The prefix of the environment variables that give the values not given
+  /// otherwise
+  env_prefix: Option<&'a str>,
+  /// Looks up the environment variables
+  env_var: fn(&str) -> Option<String>,
+}
+
+impl<'a> Bindings<'a> {
+  pub fn new(args: &'a [TemplateArg]) -> Self {
+    Self {
+      args,
+      used: vec![false; args.len()],
+      provided: Vec::new(),
+      env_prefix: None,
+      env_var: |name| env::var(name).ok(),
+    }
+  }
+
+  /// Looks up the environment variables with the function instead of taking
+  /// them from the process environment.
+  pub fn with_env(self, env_var: fn(&str) -> Option<String>) -> Self {
+    Self { env_var, ..self }
+  }
+
+  /// Takes the values not given otherwise from the environment variables
+  /// named by the prefix and the key, e.g. `NR_ARG_USER_ID` for `user-id`.
+  pub fn with_env_prefix(self, env_prefix: &'a str) -> Self {
+    Self {
+      env_prefix: Some(env_prefix),
+      ..self
+    }
+  }
+
+  fn lookup_env(&self, key: &str) -> Option<TemplateArg> {
+    let var = self.env_prefix?.to_owned()
+      + &key
+        .chars()
+        .map(|c| match c {
+          c if c.is_ascii_alphanumeric() => c.to_ascii_uppercase(),
+          _ => '_',
+        })
+        .collect::<String>();
+    (self.env_var)(&var).map(|value| TemplateArg {
+      pos: None,
+      name: Some(key.into()),
+      value: value.into(),
+      literal: false,
+    })
+  }
+
+  /// Provides a value for the argument that was not given.
+  pub fn provide(&mut self, key: &str, value: Rc<str>) {
+    self.provided.push(TemplateArg {
+      pos: None,
+      name: Some(key.into()),
+      value,
+      literal: false,
+    });
+  }
+
+  fn has(&self, key: &str) -> bool {
+    self
+      .args
+      .iter()
+      .chain(self.provided.iter())
+      .any(|arg| is_bound(arg, key))
+      || self.lookup_env(key).is_some()
+  }
+
+  /// Returns the value of the argument by name or by position.  The last one
+  /// wins if the same argument is given many times.
+  fn lookup(&mut self, key: &str) -> Option<TemplateArg> {
+    let mut value = None;
+    for (ix, arg) in self.args.iter().enumerate() {
+      if is_bound(arg, key) {
+        self.used[ix] = true;
+        value = Some(arg.clone());
+      }
+    }
+    value
+      .or_else(|| self.provided.iter().find(|arg| is_bound(arg, key)).cloned())
+      .or_else(|| self.lookup_env(key))
+  }
+
+  /// Returns the values of the unnamed positional arguments starting from the
+  /// position (counting from zero).
+  fn rest(&mut self, from: usize) -> Vec<Rc<str>> {
+    let mut values = Vec::new();
+    for (ix, arg) in self.args.iter().enumerate() {
+      if arg.name.is_none() && arg.pos.map_or(false, |p| p >= from) {
+        self.used[ix] = true;
+        values.push(arg.value.clone());
+      }
+    }
+    values
+  }
+
+  /// Fails if some argument was not used by any of the sources.
+  pub fn check_all_used(&self) -> Result<(), Error> {
+    match self.used.iter().position(|used| !used) {
+      Some(ix) => {
+        let arg = &self.args[ix];
+        Err(Error::UnusedTemplateArgument(match arg.name {
+          Some(ref name) => name.to_string(),
+          None => arg.pos.map(|p| p + 1).unwrap_or_default().to_string(),
+        }))
+      }
+      None => Ok(()),
+    }
+  }
+}
+
+fn is_bound(arg: &TemplateArg, key: &str) -> bool {
+  arg.name.as_deref() == Some(key)
+    || arg.pos.map(|p| (p + 1).to_string()).as_deref() == Some(key)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn arg(pos: Option<usize>, name: Option<&str>, value: &str) -> TemplateArg {
+    TemplateArg {
+      pos,
+      name: name.map(Into::into),
+      value: value.into(),
+      literal: false,
+    }
+  }
+
+  fn test_env(name: &str) -> Option<String> {
+    match name {
+      "NR_TEMPLATE_TEST_VAR" | "NR_TEMPLATE_TEST_ARG_USER_ID" => {
+        Some("from-env".to_owned())
+      }
+      "NR_TEMPLATE_TEST_ARG_1" => Some("2".to_owned()),
+      _ => None,
+    }
+  }
+
+  fn render(source: &str, args: &[TemplateArg]) -> Result<String, Error> {
+    let mut bindings = Bindings::new(args).with_env(test_env);
+    let rendered =
+      Template::parse("test.clj", source)?.render("test.clj", &mut bindings)?;
+    bindings.check_all_used()?;
+    Ok(rendered)
+  }
+
+  #[test]
+  fn fills_in_code_positions_only() {
+    let args = [arg(None, Some("x"), "42"), arg(Some(0), None, ":a")];
+    assert_eq!(
+      render("(f #nr[x] #nr [ 1 ]) ; #nr[y]\n\"#nr[z]\" #nr[x]", &args)
+        .unwrap(),
+      "(f 42 :a) ; #nr[y]\n\"#nr[z]\" 42"
+    );
+    assert_eq!(
+      render("#inst \"2024-01-01\"", &[]).unwrap(),
+      "#inst \"2024-01-01\""
+    );
+  }
+
+  #[test]
+  fn reports_problems_with_location() {
+    let args = [arg(None, Some("x"), "1")];
+    assert!(matches!(
+      render("(f)\n(g #nr[y])", &args),
+      Err(Error::MissingTemplateArgument(f, 2, k))
+        if f == "test.clj" && k == "y"
+    ));
+    assert!(matches!(
+      render("(f)", &args),
+      Err(Error::UnusedTemplateArgument(k)) if k == "x"
+    ));
+    assert!(matches!(
+      render("\n\n#nr[x y]", &args),
+      Err(Error::BadTemplatePlaceholder(_, 3))
+    ));
+    assert!(matches!(
+      render("#nr x", &args),
+      Err(Error::BadTemplatePlaceholder(_, 1))
+    ));
+    assert!(matches!(
+      render("(f #nr[x]", &args),
+      Err(Error::CannotParseSource(_, 1))
+    ));
+    // Syntax errors are left for the server when there are no placeholders
+    assert_eq!(render("(f", &[]).unwrap(), "(f");
+  }
+
+  #[test]
+  fn directives() {
+    let source = r#"(f #nr[x :or {:a [1 2]} :help "The \"x\""] #nr[y :or-void])
+                    (g #nr[x] #_ #nr[z] #nr[1 :name file :or "-"])"#;
+    assert_eq!(
+      render(source, &[]).unwrap(),
+      r#"(f {:a [1 2]} )
+                    (g {:a [1 2]} #_ #nr[z] "-")"#
+    );
+    let template = Template::parse("test.clj", source).unwrap();
+    assert_eq!(
+      template.args()[0],
+      ArgSpec {
+        key: "x",
+        fallback: Some(Fallback::Value("{:a [1 2]}")),
+        help: Some(r#"The "x""#.to_owned()),
+        ..Default::default()
+      }
+    );
+    assert_eq!(template.args()[2].name.as_deref(), Some("file"));
+
+    assert_eq!(
+      render("#nr[x :env NR_TEMPLATE_TEST_VAR :or 1]", &[]).unwrap(),
+      "\"from-env\""
+    );
+    assert_eq!(
+      render(
+        r#"#nr[x :env "NR_TEMPLATE_TEST_VAR"]"#,
+        &[arg(None, Some("x"), "from-arg")]
+      )
+      .unwrap(),
+      "\"from-arg\""
+    );
+    assert_eq!(
+      render("#nr[x :env NR_TEMPLATE_TEST_UNSET :or 1]", &[]).unwrap(),
+      "1"
+    );
+  }
+
+  #[test]
+  fn finds_missing_arguments() {
+    let args = [arg(None, Some("x"), "1")];
+    let template = Template::parse(
+      "test.clj",
+      "(f #nr[x] #nr[y :or 2] #nr[z :env NR_TEMPLATE_TEST_UNSET])\n#nr[1]",
+    )
+    .unwrap();
+    let mut bindings = Bindings::new(&args);
+    let missing = |bindings: &Bindings| {
+      template
+        .missing(bindings)
+        .into_iter()
+        .map(|(spec, line)| (spec.key, line))
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(missing(&bindings), [("z", 1), ("1", 2)]);
+    bindings.provide("z", "3".into());
+    bindings.provide("1", ":a".into());
+    assert!(missing(&bindings).is_empty());
+    assert_eq!(
+      template.render("test.clj", &mut bindings).unwrap(),
+      "(f 1 2 3)\n:a"
+    );
+  }
+
+  #[test]
+  fn accepts_literal_values() {
+    let literal = |value: &str| TemplateArg {
+      literal: true,
+      ..arg(None, Some("x"), value)
+    };
+    let render_as = |directive: &str, value: &str| {
+      render(&format!("#nr[x {}]", directive), &[literal(value)])
+    };
+    assert_eq!(render_as("", "{:a [1]}").unwrap(), "'{:a [1]}");
+    assert_eq!(render_as("", "(1 2 3)").unwrap(), "'(1 2 3)");
+    assert_eq!(render_as("", "[alice bob]").unwrap(), "'[alice bob]");
+    assert_eq!(render_as("", "alice").unwrap(), "'alice");
+    assert_eq!(render_as("", "##Inf").unwrap(), "##Inf");
+    assert_eq!(render_as("", "nil").unwrap(), "nil");
+    assert_eq!(render_as("", r#""s""#).unwrap(), r#""s""#);
+    assert_eq!(render_as(":clj", "(1 2 3)").unwrap(), "(1 2 3)");
+    assert_eq!(render_as(":str", r#""s""#).unwrap(), r#""s""#);
+    assert_eq!(render_as(":edn", "{:a [1]}").unwrap(), "'{:a [1]}");
+    assert!(matches!(
+      render_as(":str", "1"),
+      Err(Error::BadTemplateArgumentValue(_, 1, _, "a string"))
+    ));
+    assert!(matches!(
+      render_as(":int", "[1]"),
+      Err(Error::BadTemplateArgumentValue(_, 1, _, "an integer"))
+    ));
+  }
+
+  #[test]
+  fn arguments_from_environment() {
+    let render_with_prefix = |source: &str, args: &[TemplateArg]| {
+      let mut bindings = Bindings::new(args)
+        .with_env(test_env)
+        .with_env_prefix("NR_TEMPLATE_TEST_ARG_");
+      Template::parse("test.clj", source)?.render("test.clj", &mut bindings)
+    };
+    assert_eq!(
+      render_with_prefix("(f #nr[user-id] #nr[1])", &[]).unwrap(),
+      r#"(f "from-env" 2)"#
+    );
+    assert_eq!(
+      render_with_prefix("#nr[user-id]", &[arg(None, Some("user-id"), "a")])
+        .unwrap(),
+      r#""a""#
+    );
+    // Only with the prefix
+    assert!(render("#nr[user-id]", &[]).is_err());
+  }
+
+  #[test]
+  fn redacts_secrets_from_log() {
+    let args = [arg(None, Some("pw"), "hunter2")];
+    assert_eq!(render("#nr[pw :secret]", &args).unwrap(), r#""hunter2""#);
+    assert_eq!(
+      log::redact(r#"(login "hunter2") hunter2"#),
+      "(login ***) ***"
+    );
+    // Neither the defaults nor the values too short to redact are secrets
+    render(
+      "#nr[port :int :secret] #nr[pw2 :secret :or nil]",
+      &[arg(None, Some("port"), "1")],
+    )
+    .unwrap();
+    assert_eq!(log::redact("nil 1 port"), "nil 1 port");
+  }
+
+  #[test]
+  fn rest_argument() {
+    let args = [
+      arg(Some(0), None, "first"),
+      arg(Some(1), None, "2"),
+      arg(Some(2), Some("x"), "named"),
+      arg(Some(3), None, "3"),
+    ];
+    assert_eq!(
+      render("(f #nr[1 :str] #nr[& ids :int] #nr[x])", &args).unwrap(),
+      r#"(f "first" [2 3] "named")"#
+    );
+    assert_eq!(render("#nr[& ids]", &[]).unwrap(), "[]");
+    assert_eq!(render("#nr[& ids :or nil]", &[]).unwrap(), "nil");
+    assert!(matches!(
+      render("#nr[& ids :int]", &[arg(Some(0), None, "a")]),
+      Err(Error::BadTemplateArgumentValue(_, 1, k, "an integer")) if k == "ids"
+    ));
+    for source in ["#nr[& 1]", "#nr[&]", "#nr[& ids :env X]", "#nr[& a] #nr[a]"]
+    {
+      assert!(matches!(
+        render(source, &[]),
+        Err(Error::BadTemplatePlaceholder(_, 1))
+      ));
+    }
+  }
+
+  #[test]
+  fn interprets_values_by_type() {
+    let render_as = |directive: &str, value: &str| {
+      render(
+        &format!("#nr[x {}]", directive),
+        &[arg(None, Some("x"), value)],
+      )
+    };
+    for (value, expected) in [
+      ("42", "42"),
+      (" -1.5 ", "-1.5"),
+      (":k/w", ":k/w"),
+      ("true", "true"),
+      ("world", r#""world""#),
+      ("nil", r#""nil""#),
+      ("1 2", r#""1 2""#),
+      (r#"say "hi""#, r#""say \"hi\"""#),
+    ] {
+      assert_eq!(render_as("", value).unwrap(), expected);
+    }
+    assert_eq!(render_as(":str", "42").unwrap(), r#""42""#);
+    assert_eq!(render_as(":clj", "(inc x)").unwrap(), "(inc x)");
+    assert_eq!(render_as(":int", "0x10").unwrap(), "0x10");
+    assert_eq!(render_as(":edn", "{:a [1]}").unwrap(), "'{:a [1]}");
+    assert_eq!(render_as(":edn", "#_0 sym").unwrap(), "'sym");
+    // Only the form goes in, not the comments or the discarded forms around it
+    let render_in_list = |directive: &str, value: &str| {
+      render(
+        &format!("(f #nr[x {}] y)", directive),
+        &[arg(None, Some("x"), value)],
+      )
+    };
+    for (directive, value, expected) in [
+      ("", "42 ;", "(f 42 y)"),
+      ("", "42 #_ 0", "(f 42 y)"),
+      (":int", "1 ; rm", "(f 1 y)"),
+      (":edn", "{:a 1} ;", "(f '{:a 1} y)"),
+      (":edn", "#inst \"2024\" #_z", "(f '#inst \"2024\" y)"),
+      (":clj", "(g 1) ; (h)", "(f (g 1) y)"),
+      (":clj", "a ; b\nc", "(f a ; b\nc y)"),
+    ] {
+      assert_eq!(render_in_list(directive, value).unwrap(), expected);
+    }
+    for (directive, value, expected) in [
+      (":clj", "(inc x", "Clojure code"),
+      (":int", "1.5", "an integer"),
+      (":int", "1 2", "an integer"),
+      (":edn", "1 2", "an EDN value"),
+      (":edn", "#(inc %)", "an EDN value"),
+      (":edn", "'x", "an EDN value"),
+    ] {
+      assert!(matches!(
+        render_as(directive, value),
+        Err(Error::BadTemplateArgumentValue(_, 1, k, e))
+          if k == "x" && e == expected
+      ));
+    }
+    // Defaults are code and go in as they are
+    assert_eq!(render("#nr[x :str :or nil]", &[]).unwrap(), "nil");
+    assert!(matches!(
+      render("#nr[x :int] #nr[x :str]", &[]),
+      Err(Error::ConflictingTemplateDirective(_, 1, ":str", _))
+    ));
+  }
+
+  #[test]
+  fn conflicting_directives() {
+    assert!(matches!(
+      render("(f #nr[x :or 1])\n(g #nr[x :or 2])", &[]),
+      Err(Error::ConflictingTemplateDirective(_, 2, ":or", k)) if k == "x"
+    ));
+    assert!(matches!(
+      render("#nr[x :or 1 :or-void]", &[]),
+      Err(Error::ConflictingTemplateDirective(_, 1, ":or-void", _))
+    ));
+    assert!(matches!(
+      render(r#"#nr[x :help "a"] #nr[x :help "b"]"#, &[]),
+      Err(Error::ConflictingTemplateDirective(_, 1, ":help", _))
+    ));
+    assert!(matches!(
+      render("#nr[x :or #nr[y]]", &[]),
+      Err(Error::BadTemplatePlaceholder(_, 1))
+    ));
+    // Repeating the same directive is fine
+    assert_eq!(render("#nr[x :or 1] #nr[x :or 1]", &[]).unwrap(), "1 1");
+    // Unknown and incomplete directives are not
+    assert!(matches!(
+      render("#nr[x :default 1]", &[]),
+      Err(Error::BadTemplatePlaceholder(_, 1))
+    ));
+    assert!(matches!(
+      render("#nr[x :or]", &[]),
+      Err(Error::BadTemplatePlaceholder(_, 1))
+    ));
+  }
+}
@@ -0,0 +1,322 @@
+// transcript.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Recording nREPL sessions and replaying them.
+//!
+//! A transcript is a sequence of bencoded dictionaries, one per frame, with
+//! the keys `at` (milliseconds since the connection was opened), `dir`
+//! (`sent` or `received`), and `frame` (the frame as it went over the wire).
+
+use std::{
+  collections::{HashMap, VecDeque},
+  fs,
+  io::{self, Read, Write},
+  path::Path,
+  time,
+};
+
+use serde_bencode::value::Value;
+
+use crate::{
+  bencode,
+  log::{self, Bencode},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+  Sent,
+  Received,
+}
+
+impl Direction {
+  fn as_str(self) -> &'static str {
+    match self {
+      Direction::Sent => "sent",
+      Direction::Received => "received",
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+  /// Milliseconds since the connection was opened
+  pub at: u64,
+  pub direction: Direction,
+  pub bytes: Vec<u8>,
+}
+
+impl Frame {
+  pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+    let record = Value::Dict(HashMap::from([
+      (b"at".to_vec(), Value::Int(self.at as i64)),
+      (
+        b"dir".to_vec(),
+        Value::Bytes(self.direction.as_str().as_bytes().to_vec()),
+      ),
+      (b"frame".to_vec(), Value::Bytes(self.bytes.clone())),
+    ]));
+    let bytes = serde_bencode::to_bytes(&record)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    w.write_all(&bytes)
+  }
+
+  fn from_record(record: &[u8]) -> Option<Self> {
+    let Ok(Value::Dict(mut record)) = serde_bencode::from_bytes(record) else {
+      return None;
+    };
+    let at = match record.remove(&b"at"[..])? {
+      Value::Int(at) => u64::try_from(at).ok()?,
+      _ => return None,
+    };
+    let direction = match record.remove(&b"dir"[..])? {
+      Value::Bytes(dir) if dir == b"sent" => Direction::Sent,
+      Value::Bytes(dir) if dir == b"received" => Direction::Received,
+      _ => return None,
+    };
+    let bytes = match record.remove(&b"frame"[..])? {
+      Value::Bytes(bytes) => bytes,
+      _ => return None,
+    };
+    Some(Self {
+      at,
+      direction,
+      bytes,
+    })
+  }
+}
+
+/// Reads the frames of a transcript.
+pub fn read(path: &Path) -> io::Result<Vec<Frame>> {
+  let contents = fs::read(path)?;
+  let mut rest = &contents[..];
+  let mut frames = Vec::new();
+  while !rest.is_empty() {
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, "bad transcript");
+    let (_, len) = bencode::scan_next(rest).map_err(|_| bad())?;
+    frames.push(Frame::from_record(&rest[..len]).ok_or_else(bad)?);
+    rest = &rest[len..];
+  }
+  Ok(frames)
+}
+
+/// Writes the frames to a transcript as they are sent and received
+#[derive(Debug)]
+pub struct Recorder {
+  file: fs::File,
+  opened: time::Instant,
+}
+
+impl Recorder {
+  pub fn create(path: &Path) -> io::Result<Self> {
+    Ok(Self {
+      file: fs::File::create(path)?,
+      opened: time::Instant::now(),
+    })
+  }
+
+  /// Records the frame with the secrets redacted.  Each frame is written right
+  /// away so that the transcript is there even if the program is killed.
+  pub fn record(
+    &mut self,
+    direction: Direction,
+    bytes: &[u8],
+  ) -> io::Result<()> {
+    Frame {
+      at: self.opened.elapsed().as_millis() as u64,
+      direction,
+      bytes: log::redact_bencode(bytes),
+    }
+    .write_to(&mut self.file)
+  }
+}
+
+/// A stand-in for the server that answers with the received frames of a
+/// transcript.
+///
+/// The sent frames must match the ones in the transcript byte for byte.  The
+/// server appears to disconnect when the transcript runs out.  The timing of
+/// the original session is not reproduced.
+#[derive(Debug)]
+pub struct Replay {
+  frames: VecDeque<Frame>,
+  written: Vec<u8>,
+  readable: VecDeque<u8>,
+}
+
+impl Replay {
+  pub fn new(frames: Vec<Frame>) -> Self {
+    let mut replay = Self {
+      frames: frames.into(),
+      written: Vec::new(),
+      readable: VecDeque::new(),
+    };
+    replay.release_received();
+    replay
+  }
+
+  /// Makes the received frames up to the next sent frame readable.
+  fn release_received(&mut self) {
+    while let Some(frame) = self.frames.front() {
+      if frame.direction != Direction::Received {
+        break;
+      }
+      let frame = self.frames.pop_front().unwrap();
+      self.readable.extend(frame.bytes);
+    }
+  }
+
+  fn check_sent(&mut self, sent: &[u8]) -> io::Result<()> {
+    match self.frames.front() {
+      // The recorded frames have the secrets redacted
+      Some(expected)
+        if expected.bytes == sent
+          || expected.bytes == log::redact_bencode(sent) =>
+      {
+        self.frames.pop_front();
+        self.release_received();
+        Ok(())
+      }
+      Some(expected) => {
+        let err = io::Error::new(
+          io::ErrorKind::InvalidData,
+          format!(
+            "replay diverged; expected {} but got {}",
+            Bencode(&expected.bytes),
+            Bencode(sent)
+          ),
+        );
+        // Nothing that follows makes sense anymore
+        self.frames.clear();
+        self.readable.clear();
+        Err(err)
+      }
+      None => Err(io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "the transcript ended",
+      )),
+    }
+  }
+}
+
+impl Read for Replay {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.readable.is_empty() && !self.frames.is_empty() {
+      // The real server would keep us waiting forever
+      return Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "the transcript expects a request before a response",
+      ));
+    }
+    self.readable.read(buf)
+  }
+}
+
+impl Write for Replay {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.written.extend_from_slice(buf);
+    while let Ok((_, len)) = bencode::scan_next(&self.written) {
+      let sent = self.written.drain(..len).collect::<Vec<_>>();
+      self.check_sent(&sent)?;
+    }
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn frame(direction: Direction, bytes: &[u8]) -> Frame {
+    Frame {
+      at: 0,
+      direction,
+      bytes: bytes.to_vec(),
+    }
+  }
+
+  #[test]
+  fn transcript_round_trip() {
+    let path = std::env::temp_dir()
+      .join(format!("nr-transcript-{}.bencode", std::process::id()));
+    let mut recorder = Recorder::create(&path).unwrap();
+    recorder.record(Direction::Sent, b"d2:op5:clonee").unwrap();
+    recorder
+      .record(Direction::Received, b"d11:new-session2:s1e")
+      .unwrap();
+    let frames = read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].direction, Direction::Sent);
+    assert_eq!(frames[0].bytes, b"d2:op5:clonee");
+    assert_eq!(frames[1].direction, Direction::Received);
+    assert_eq!(frames[1].bytes, b"d11:new-session2:s1e");
+  }
+
+  #[test]
+  fn records_secrets_redacted() {
+    let path = std::env::temp_dir().join(format!(
+      "nr-transcript-secret-{}.bencode",
+      std::process::id()
+    ));
+    log::add_secret("transcript-secret");
+    let sent = b"d4:code19:\"transcript-secret\"2:op4:evale";
+    let mut recorder = Recorder::create(&path).unwrap();
+    recorder.record(Direction::Sent, sent).unwrap();
+    let frames = read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(frames[0].bytes, b"d4:code5:\"***\"2:op4:evale");
+    // The same session replays although the secret is not in the transcript
+    let mut replay = Replay::new(frames);
+    replay.write_all(sent).unwrap();
+  }
+
+  #[test]
+  fn replay_answers_matching_requests() {
+    let mut replay = Replay::new(vec![
+      frame(Direction::Sent, b"d2:op5:clonee"),
+      frame(Direction::Received, b"d2:id1:ae"),
+      frame(Direction::Received, b"d2:id1:be"),
+      frame(Direction::Sent, b"d2:op5:closee"),
+    ]);
+    let mut response = String::new();
+    // Nothing to read before the request
+    assert!(replay.read_to_string(&mut response).is_err());
+    // Partial writes are fine
+    replay.write_all(b"d2:op5:").unwrap();
+    replay.write_all(b"clonee").unwrap();
+    let mut buf = [0; 64];
+    let len = replay.read(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"d2:id1:aed2:id1:be");
+    let err = replay.write_all(b"d2:op4:evale").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains(r#"expected {"op" "close"}"#));
+  }
+
+  #[test]
+  fn replay_disconnects_at_the_end() {
+    let mut replay = Replay::new(vec![
+      frame(Direction::Sent, b"d2:op5:clonee"),
+      frame(Direction::Received, b"d2:id1:ae"),
+    ]);
+    replay.write_all(b"d2:op5:clonee").unwrap();
+    let mut received = Vec::new();
+    replay.read_to_end(&mut received).unwrap();
+    assert_eq!(received, b"d2:id1:ae");
+  }
+}
@@ -259,26 +259,20 @@ mod test {
   fn parse_good_version_strings() {
     use Version::*;
 
-    assert!(match "1".parse::<Version>() {
-      Ok(Major(1)) => true,
-      _ => false,
-    });
-    assert!(match "1.2".parse::<Version>() {
-      Ok(MajorMinor(1, 2)) => true,
-      _ => false,
-    });
-    assert!(match "1.2.3".parse::<Version>() {
-      Ok(MajorMinorPatch(1, 2, 3)) => true,
-      _ => false,
-    });
-    assert!(match "123.456.789".parse::<Version>() {
-      Ok(MajorMinorPatch(123, 456, 789)) => true,
-      _ => false,
-    });
-    assert!(match "0.0.0".parse::<Version>() {
-      Ok(MajorMinorPatch(0, 0, 0)) => true,
-      _ => false,
-    });
+    assert!(matches!("1".parse::<Version>(), Ok(Major(1))));
+    assert!(matches!("1.2".parse::<Version>(), Ok(MajorMinor(1, 2))));
+    assert!(matches!(
+      "1.2.3".parse::<Version>(),
+      Ok(MajorMinorPatch(1, 2, 3))
+    ));
+    assert!(matches!(
+      "123.456.789".parse::<Version>(),
+      Ok(MajorMinorPatch(123, 456, 789))
+    ));
+    assert!(matches!(
+      "0.0.0".parse::<Version>(),
+      Ok(MajorMinorPatch(0, 0, 0))
+    ));
   }
 
   #[test]
@@ -0,0 +1,576 @@
+// tests/fake_server.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Runs `nr` against a fake nREPL server.
+
+use std::{
+  env, fs,
+  io::Write,
+  net::TcpListener,
+  path::PathBuf,
+  process::{self, Command, Output, Stdio},
+};
+
+use nreplops_tool_dev::fake_nrepl::{FakeServer, Reply, Script};
+
+/// A scratch directory that serves as the working and home directory so that
+/// no port or hosts files of the user get picked up
+struct Scratch(PathBuf);
+
+impl Scratch {
+  fn new(name: &str) -> Self {
+    let path = env::temp_dir().join(format!(
+      "nr-fake-server-test-{}-{}",
+      name,
+      process::id()
+    ));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    Self(path)
+  }
+}
+
+impl Drop for Scratch {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.0);
+  }
+}
+
+fn nr(scratch: &Scratch, args: &[&str], stdin: &str) -> Output {
+  nr_with_env(scratch, args, &[], stdin)
+}
+
+fn nr_with_env(
+  scratch: &Scratch,
+  args: &[&str],
+  vars: &[(&str, &str)],
+  stdin: &str,
+) -> Output {
+  let mut child = Command::new(env!("CARGO_BIN_EXE_nr"))
+    .args(args)
+    .envs(vars.iter().copied())
+    .current_dir(&scratch.0)
+    .env("HOME", &scratch.0)
+    .env_remove("XDG_CONFIG_HOME")
+    .env_remove("XDG_CACHE_HOME")
+    .env_remove("NR_PORT")
+    .env_remove("NREPL_PORT")
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .unwrap();
+  child
+    .stdin
+    .take()
+    .unwrap()
+    .write_all(stdin.as_bytes())
+    .unwrap();
+  child.wait_with_output().unwrap()
+}
+
+fn port(server: &FakeServer) -> String {
+  format!("127.0.0.1:{}", server.port())
+}
+
+fn stdout(output: &Output) -> String {
+  String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+  String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn evaluates_expressions_in_order() {
+  let scratch = Scratch::new("order");
+  let server = FakeServer::start(
+    Script::new()
+      .on_eval("(inc 1)", [Reply::Value("2".into())])
+      .on_eval("(inc 2)", [Reply::Value("3".into())]),
+  )
+  .unwrap();
+  let output = nr(
+    &scratch,
+    &["-p", &port(&server), "-e", "(inc 1)", "-e", "(inc 2)"],
+    "",
+  );
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(stdout(&output), "2\n3\n");
+  assert_eq!(server.ops(), ["clone", "eval", "eval", "close"]);
+}
+
+#[test]
+fn evaluates_expressions_and_files_in_given_order() {
+  let scratch = Scratch::new("mixed-order");
+  let server = FakeServer::start(Script::new()).unwrap();
+  fs::write(scratch.0.join("b.clj"), ":b").unwrap();
+  let output = nr(
+    &scratch,
+    &[
+      "-p",
+      &port(&server),
+      "-e",
+      ":a",
+      "-f",
+      "b.clj",
+      "-f",
+      "-",
+      "-e",
+      ":d",
+    ],
+    ":c",
+  );
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(stdout(&output), ":a\n:b\n:c\n:d\n");
+
+  fs::write(scratch.0.join("script.clj"), "#nr[1]").unwrap();
+  let output = nr(
+    &scratch,
+    &[
+      "-p",
+      &port(&server),
+      "-e",
+      ":pre",
+      "-!",
+      "script.clj",
+      "-e",
+      ":post",
+      ":arg",
+    ],
+    "",
+  );
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(stdout(&output), ":pre\n:arg\n:post\n");
+}
+
+#[test]
+fn routes_output_streams() {
+  let scratch = Scratch::new("streams");
+  let server = FakeServer::start(Script::new().on_eval(
+    "(run)",
+    [
+      Reply::Out("working\n".into()),
+      Reply::Err("careful\n".into()),
+      Reply::Value(":ok".into()),
+    ],
+  ))
+  .unwrap();
+  let output = nr(&scratch, &["-p", &port(&server), "-e", "(run)"], "");
+  assert!(output.status.success());
+  assert_eq!(stdout(&output), "working\n:ok\n");
+  assert_eq!(stderr(&output), "careful\n");
+
+  let output = nr(
+    &scratch,
+    &[
+      "-p",
+      &port(&server),
+      "--no-out",
+      "--err",
+      "err.txt",
+      "--no-results",
+      "-e",
+      "(run)",
+    ],
+    "",
+  );
+  assert!(output.status.success());
+  assert_eq!(stdout(&output), "");
+  assert_eq!(stderr(&output), "");
+  assert_eq!(
+    fs::read_to_string(scratch.0.join("err.txt")).unwrap(),
+    "careful\n"
+  );
+}
+
+#[test]
+fn shows_sent_code() {
+  let scratch = Scratch::new("exprs");
+  fs::write(
+    scratch.0.join("app.clj"),
+    "(ns app)\n\n(login #nr[pw :secret])\n",
+  )
+  .unwrap();
+  let server = FakeServer::start(
+    Script::new().on_eval("(+ 1 2)", [Reply::Value("3".into())]),
+  )
+  .unwrap();
+  let output = nr(
+    &scratch,
+    &[
+      "-p",
+      &port(&server),
+      "--dry-run",
+      "-e",
+      "(+ 1 2)",
+      "-f",
+      "app.clj",
+      "-a",
+      "pw=hunter2",
+    ],
+    "",
+  );
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert!(server.requests().is_empty());
+  let route = format!(";; route: {}", port(&server));
+  assert_eq!(
+    stdout(&output),
+    format!(
+      "{}\n\n\
+       ;; line 1 of <input> in user\n(+ 1 2)\n\n\
+       ;; line 1 of {1} in user\n(ns app)\n\n\
+       ;; line 3 of {1} in app\n(login ***)\n",
+      route,
+      scratch.0.join("app.clj").canonicalize().unwrap().display()
+    )
+  );
+
+  let output = nr(
+    &scratch,
+    &[
+      "-p",
+      &port(&server),
+      "--exprs-to",
+      "exprs.clj",
+      "-e",
+      "(+ 1 2)",
+    ],
+    "",
+  );
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(stdout(&output), "3\n");
+  assert_eq!(
+    fs::read_to_string(scratch.0.join("exprs.clj")).unwrap(),
+    "(+ 1 2)\n"
+  );
+}
+
+#[test]
+fn reads_program_from_stdin() {
+  let scratch = Scratch::new("stdin");
+  let server = FakeServer::start(Script::new()).unwrap();
+  let output = nr(&scratch, &["-p", &port(&server)], "(+ 1 2)\n");
+  assert!(output.status.success());
+  assert_eq!(stdout(&output), "(+ 1 2)\n");
+  let requests = server.requests();
+  assert_eq!(requests[1].op(), "eval");
+  assert_eq!(requests[1].get("code"), Some("(+ 1 2)"));
+}
+
+#[test]
+fn fills_in_template_arguments() {
+  let scratch = Scratch::new("args");
+  let server = FakeServer::start(Script::new()).unwrap();
+  let output = nr(
+    &scratch,
+    &[
+      "-p",
+      &port(&server),
+      "-a",
+      "who=world",
+      "-e",
+      "(greet #nr[who])",
+    ],
+    "",
+  );
+  assert!(output.status.success());
+  assert_eq!(server.requests()[1].get("code"), Some(r#"(greet "world")"#));
+}
+
+#[test]
+fn reads_template_arguments_from_files() {
+  let scratch = Scratch::new("args-files");
+  let server = FakeServer::start(Script::new()).unwrap();
+  fs::write(
+    scratch.0.join("args.json"),
+    r#"{"opts": {"ids": [1, 2]}, "who": "json"}"#,
+  )
+  .unwrap();
+  fs::write(scratch.0.join("args.edn"), "{:who :edn}").unwrap();
+  fs::write(
+    scratch.0.join("data.edn"),
+    "{:ids (1 2 3) :users [alice bob]}",
+  )
+  .unwrap();
+  fs::write(scratch.0.join("note.txt"), "hello\n").unwrap();
+  let output = nr(
+    &scratch,
+    &[
+      "-p",
+      &port(&server),
+      "--args-file",
+      "args.json",
+      "-a",
+      "note=@note.txt",
+      "-e",
+      "(run #nr[opts] #nr[who] #nr[note])",
+    ],
+    "",
+  );
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(
+    server.requests()[1].get("code"),
+    Some(r#"(run '{"ids" [1 2]} "json" "hello")"#)
+  );
+
+  let output = nr(
+    &scratch,
+    &[
+      "-p",
+      &port(&server),
+      "--args-file",
+      "args.edn",
+      "-e",
+      "#nr[who]",
+    ],
+    "",
+  );
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(stdout(&output), ":edn\n");
+
+  // The lists and symbols are data, not code
+  let output = nr(
+    &scratch,
+    &[
+      "-p",
+      &port(&server),
+      "--args-file",
+      "data.edn",
+      "-e",
+      "(f #nr[ids] #nr[users])",
+    ],
+    "",
+  );
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(
+    server
+      .requests()
+      .iter()
+      .rev()
+      .find(|r| r.op() == "eval")
+      .and_then(|r| r.get("code")),
+    Some("(f '(1 2 3) '[alice bob])")
+  );
+
+  fs::write(scratch.0.join("bad.json"), "[1]").unwrap();
+  let output = nr(
+    &scratch,
+    &["-p", &port(&server), "--args-file", "bad.json"],
+    "",
+  );
+  assert_eq!(output.status.code(), Some(1));
+  assert_eq!(
+    stderr(&output),
+    "Error: bad template arguments file bad.json: expected an object\n"
+  );
+}
+
+#[test]
+fn reads_template_arguments_from_environment() {
+  let scratch = Scratch::new("env-args");
+  let server = FakeServer::start(Script::new()).unwrap();
+  let output = nr_with_env(
+    &scratch,
+    &[
+      "-p",
+      &port(&server),
+      "--trace-wire",
+      "-a",
+      "user=admin",
+      "-e",
+      "(login #nr[user] #nr[pw :secret])",
+    ],
+    &[("NR_ARG_USER", "nobody"), ("NR_ARG_PW", "hunter2")],
+    "",
+  );
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(
+    server.requests()[1].get("code"),
+    Some(r#"(login "admin" "hunter2")"#)
+  );
+  assert!(stderr(&output).contains("***"));
+  assert!(!stderr(&output).contains("hunter2"));
+
+  let output = nr_with_env(
+    &scratch,
+    &[
+      "-p",
+      &port(&server),
+      "--env-prefix",
+      "MY_",
+      "-e",
+      "#nr[who]",
+    ],
+    &[("MY_WHO", ":me")],
+    "",
+  );
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(stdout(&output), ":me\n");
+}
+
+#[test]
+fn checks_template_arguments_before_sending() {
+  let scratch = Scratch::new("missing-args");
+  let server = FakeServer::start(Script::new()).unwrap();
+  let output = nr(
+    &scratch,
+    &[
+      "-p",
+      &port(&server),
+      "-a",
+      "who=world",
+      "-e",
+      "(greet #nr[whom])",
+    ],
+    "",
+  );
+  assert_eq!(output.status.code(), Some(1));
+  assert_eq!(
+    stderr(&output),
+    "Error: no value for template argument whom on line 1 of <input>\n"
+  );
+  assert!(server.requests().is_empty());
+
+  let output = nr(
+    &scratch,
+    &[
+      "-p",
+      &port(&server),
+      "-e",
+      "(greet #nr[who])",
+      "-e",
+      "#nr[how]",
+    ],
+    "",
+  );
+  assert_eq!(output.status.code(), Some(1));
+  assert_eq!(
+    stderr(&output),
+    "Error: no values for template arguments who (line 1 of <input>), how \
+     (line 1 of <input>)\n"
+  );
+  assert!(server.requests().is_empty());
+}
+
+#[test]
+fn passes_remaining_arguments_to_script() {
+  let scratch = Scratch::new("rest-args");
+  let server = FakeServer::start(Script::new()).unwrap();
+  fs::write(scratch.0.join("sum.clj"), "(apply + #nr[& ns :int])\n").unwrap();
+  let output = nr(
+    &scratch,
+    &["-p", &port(&server), "-!", "sum.clj", "1", "2", "3"],
+    "",
+  );
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(server.requests()[1].get("code"), Some("(apply + [1 2 3])"));
+}
+
+#[test]
+fn prints_script_help() {
+  let scratch = Scratch::new("script-help");
+  fs::write(
+    scratch.0.join("greet.clj"),
+    ";; Greets someone.\n(greet #nr[1 :name who :help \"Who to greet\"])\n",
+  )
+  .unwrap();
+  let output = nr(&scratch, &["-!", "0.3", "greet.clj", "--help"], "");
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(
+    stdout(&output),
+    "Greets someone.\n\
+     \n\
+     Usage: greet.clj [OPTIONS] <who>\n\
+     \n\
+     Arguments:\n  \
+     <who>  Who to greet\n\
+     \n\
+     Requires nr version 0.3.0..0.4.0\n"
+  );
+}
+
+#[test]
+fn finds_port_file() {
+  let scratch = Scratch::new("port-file");
+  let server = FakeServer::start(Script::new()).unwrap();
+  fs::write(scratch.0.join(".nrepl-port"), server.port().to_string()).unwrap();
+  let output = nr(&scratch, &["-e", ":hello"], "");
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(stdout(&output), ":hello\n");
+}
+
+#[test]
+fn stops_when_host_assertion_fails() {
+  let scratch = Scratch::new("assert");
+  let server = FakeServer::start(
+    Script::new().on_eval("(prod?)", [Reply::Value("false".into())]),
+  )
+  .unwrap();
+  let output = nr(
+    &scratch,
+    &[
+      "-p",
+      &port(&server),
+      "--assert-form",
+      "(prod?)",
+      "-e",
+      "(drop-db)",
+    ],
+    "",
+  );
+  assert_eq!(output.status.code(), Some(1));
+  assert!(stderr(&output).starts_with("Error: "));
+  assert_eq!(server.ops(), ["clone", "eval", "close"]);
+}
+
+#[test]
+fn fails_when_host_disconnects() {
+  let scratch = Scratch::new("disconnect");
+  let server = FakeServer::start(Script::new().on_eval(
+    "(crash)",
+    [Reply::Out("going down\n".into()), Reply::Disconnect],
+  ))
+  .unwrap();
+  let output = nr(&scratch, &["-p", &port(&server), "-e", "(crash)"], "");
+  assert_eq!(output.status.code(), Some(1));
+  assert_eq!(stdout(&output), "going down\n");
+  assert_eq!(stderr(&output), "Error: host disconnected unexpectedly\n");
+}
+
+#[test]
+fn fails_when_host_refuses_session() {
+  let scratch = Scratch::new("clone");
+  let server = FakeServer::start(Script::new().disconnect_on_clone()).unwrap();
+  let output = nr(&scratch, &["-p", &port(&server), "-e", "1"], "");
+  assert_eq!(output.status.code(), Some(1));
+  assert_eq!(stderr(&output), "Error: host disconnected unexpectedly\n");
+}
+
+#[test]
+fn fails_when_nobody_listens() {
+  let scratch = Scratch::new("refused");
+  let port = {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+  };
+  let output = nr(
+    &scratch,
+    &["-p", &format!("127.0.0.1:{}", port), "-e", "1"],
+    "",
+  );
+  assert_eq!(output.status.code(), Some(1));
+  assert!(stderr(&output).starts_with("Error: "));
+}
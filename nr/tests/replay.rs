@@ -0,0 +1,166 @@
+// tests/replay.rs
+// Copyright 2024 Matti Hänninen
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Runs `nr` against recorded sessions.
+
+use std::{
+  collections::HashMap,
+  env, fs,
+  path::PathBuf,
+  process::{Command, Output},
+};
+
+use serde_bencode::value::Value;
+
+use nreplops_tool::transcript::{self, Direction, Frame};
+
+fn s(s: &str) -> Value {
+  Value::Bytes(s.as_bytes().to_vec())
+}
+
+fn msg(entries: &[(&str, Value)]) -> Vec<u8> {
+  let dict = entries
+    .iter()
+    .map(|(k, v)| (k.as_bytes().to_vec(), v.clone()))
+    .collect::<HashMap<_, _>>();
+  serde_bencode::to_bytes(&Value::Dict(dict)).unwrap()
+}
+
+fn sent(entries: &[(&str, Value)]) -> Frame {
+  Frame {
+    at: 0,
+    direction: Direction::Sent,
+    bytes: msg(entries),
+  }
+}
+
+fn received(entries: &[(&str, Value)]) -> Frame {
+  Frame {
+    at: 0,
+    direction: Direction::Received,
+    bytes: msg(entries),
+  }
+}
+
+fn done() -> Value {
+  Value::List(vec![s("done")])
+}
+
+/// A session evaluating `(+ 1 2)` that prints along the way
+fn eval_session() -> Vec<Frame> {
+  vec![
+    sent(&[("op", s("clone")), ("id", s(""))]),
+    received(&[
+      ("id", s("")),
+      ("session", s("s0")),
+      ("new-session", s("s1")),
+      ("status", done()),
+    ]),
+    sent(&[
+      ("op", s("eval")),
+      ("id", s("s1:1")),
+      ("session", s("s1")),
+      ("code", s("(+ 1 2)")),
+      ("line", Value::Int(1)),
+      ("column", Value::Int(1)),
+    ]),
+    received(&[("id", s("s1:1")), ("session", s("s1")), ("out", s("hi\n"))]),
+    received(&[("id", s("s1:1")), ("session", s("s1")), ("value", s("3"))]),
+    received(&[("id", s("s1:1")), ("session", s("s1")), ("status", done())]),
+    sent(&[
+      ("op", s("close")),
+      ("id", s("s1:close")),
+      ("session", s("s1")),
+    ]),
+    received(&[
+      ("id", s("s1:close")),
+      ("session", s("s1")),
+      ("status", Value::List(vec![s("done"), s("session-closed")])),
+    ]),
+  ]
+}
+
+struct Transcript(PathBuf);
+
+impl Transcript {
+  fn new(name: &str, frames: &[Frame]) -> Self {
+    let path = env::temp_dir().join(format!(
+      "nr-replay-test-{}-{}",
+      name,
+      std::process::id()
+    ));
+    let mut file = fs::File::create(&path).unwrap();
+    for frame in frames {
+      frame.write_to(&mut file).unwrap();
+    }
+    Self(path)
+  }
+}
+
+impl Drop for Transcript {
+  fn drop(&mut self) {
+    let _ = fs::remove_file(&self.0);
+  }
+}
+
+fn nr(args: &[&str]) -> Output {
+  Command::new(env!("CARGO_BIN_EXE_nr"))
+    .args(args)
+    .env_remove("NR_PORT")
+    .env_remove("NREPL_PORT")
+    .output()
+    .unwrap()
+}
+
+#[test]
+fn replays_evaluation() {
+  let t = Transcript::new("eval", &eval_session());
+  let output = nr(&["--replay", t.0.to_str().unwrap(), "-e", "(+ 1 2)"]);
+  assert!(output.status.success());
+  assert_eq!(String::from_utf8_lossy(&output.stdout), "hi\n3\n");
+}
+
+#[test]
+fn fails_when_replay_diverges() {
+  let t = Transcript::new("diverge", &eval_session());
+  let output = nr(&["--replay", t.0.to_str().unwrap(), "-e", "(+ 1 3)"]);
+  assert_eq!(output.status.code(), Some(1));
+  assert!(String::from_utf8_lossy(&output.stderr).contains("replay diverged"));
+}
+
+#[test]
+fn records_what_it_replays() {
+  let t = Transcript::new("original", &eval_session());
+  let r = Transcript::new("recorded", &[]);
+  let output = nr(&[
+    "--replay",
+    t.0.to_str().unwrap(),
+    "--record",
+    r.0.to_str().unwrap(),
+    "-e",
+    "(+ 1 2)",
+  ]);
+  assert!(output.status.success());
+  let strip = |frames: Vec<Frame>| {
+    frames
+      .into_iter()
+      .map(|f| (f.direction, f.bytes))
+      .collect::<Vec<_>>()
+  };
+  assert_eq!(
+    strip(transcript::read(&r.0).unwrap()),
+    strip(eval_session())
+  );
+}